@@ -18,24 +18,30 @@ use millegrilles_common_rust::middleware::{sauvegarder_traiter_transaction_seria
 use millegrilles_common_rust::millegrilles_cryptographie::chiffrage_cles::{Cipher, CleChiffrageHandler};
 use millegrilles_common_rust::millegrilles_cryptographie::chiffrage_mgs4::{CipherMgs4, CleSecreteCipher};
 use millegrilles_common_rust::millegrilles_cryptographie::maitredescles::generer_cle_avec_ca;
-use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::MessageMilleGrillesBufferDefault;
-use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::optionepochseconds;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{MessageMilleGrillesBufferDefault, MessageMilleGrillesOwned};
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, optionepochseconds};
 use millegrilles_common_rust::millegrilles_cryptographie::x25519::CleSecreteX25519;
 use millegrilles_common_rust::millegrilles_cryptographie::x509::{EnveloppeCertificat, lire_idmg};
-use millegrilles_common_rust::mongo_dao::MongoDao;
-use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use millegrilles_common_rust::mongo_dao::{convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument, UpdateOptions};
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
 use millegrilles_common_rust::recepteur_messages::{MessageValide, TypeMessage};
 use millegrilles_common_rust::serde_json::json;
 use millegrilles_common_rust::tokio_stream::StreamExt;
 use millegrilles_common_rust::tokio::time as tokio_time;
+use millegrilles_common_rust::uuid::Uuid;
 use serde::{Deserialize, Serialize};
 
 use crate::constantes;
 use crate::constantes::DOMAINE_NOM;
 use crate::domaine_hebergement::GestionnaireDomaineHebergement;
-use crate::structure_donnees::ClientHebergementRow;
-use crate::transactions::{TransactionAjouterFichier, TransactionSauvegarderClient};
+use crate::entretien::verifier_seuils_quota;
+use crate::etat_clients::{charger_debits_mois, charger_ecritures_mois, charger_plan, charger_quota_client, charger_utilisation_client, releve_deja_debite, verifier_quota_fichier};
+use crate::periodes::debut_jour;
+use crate::releves::calculer_releve;
+use crate::structure_donnees::{ClientHebergementRow, DemandeHebergementRow, InvitationRow, PlanHebergementRow, ReleveRow, ServiceCatalogueRow, TransfertJourRow};
+use crate::transactions::{TransactionAjouterFichier, TransactionApprouverDemande, TransactionCreerInvitation, TransactionDemandeHebergement, TransactionEcritureGrandLivre, TransactionFinaliserReleve, TransactionMajClient, TransactionProlongerClient, TransactionRefuserDemande, TransactionRetirerFichier, TransactionRetirerServiceCatalogue, TransactionSauvegarderClient, TransactionSupprimerClient, TransactionUtiliserInvitation};
+use crate::verification_client::{ResultatVerificationClient, verifier_acces_operateur, verifier_requete_client};
 
 pub async fn consommer_commande<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -53,6 +59,23 @@ pub async fn consommer_commande<M>(gestionnaire: &GestionnaireDomaineHebergement
         // Commandes standard
         constantes::TRANSACTION_SAUVEGARDER_CLIENT => commande_sauvegarder_client(gestionnaire, middleware, message).await,
         constantes::TRANSACTION_AJOUTER_FICHIER => commande_ajouter_fichier(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_RETIRER_FICHIER => commande_retirer_fichier(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_SUPPRIMER_CLIENT => commande_supprimer_client(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_MAJ_CLIENT => commande_maj_client(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_PROLONGER_CLIENT => commande_prolonger_client(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_DEMANDE_HEBERGEMENT => commande_demande_hebergement(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_APPROUVER_DEMANDE => commande_approuver_demande(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_REFUSER_DEMANDE => commande_refuser_demande(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_CREER_INVITATION => commande_creer_invitation(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_UTILISER_INVITATION => commande_utiliser_invitation(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_AJOUTER_SERVICE_CATALOGUE => commande_ajouter_service_catalogue(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_RETIRER_SERVICE_CATALOGUE => commande_retirer_service_catalogue(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_SAUVEGARDER_PLAN => commande_sauvegarder_plan(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_RAPPORTER_TRANSFERT => commande_rapporter_transfert(gestionnaire, middleware, message).await,
+        constantes::COMMANDE_GENERER_RELEVES => commande_generer_releves(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_FINALISER_RELEVE => commande_finaliser_releve(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_CREDITER_CLIENT => commande_crediter_client(gestionnaire, middleware, message).await,
+        constantes::TRANSACTION_DEBITER_CLIENT => commande_debiter_client(gestionnaire, middleware, message).await,
         // Commande inconnue
         _ => Err(Error::String(format!("consommer_commande: Commande {} inconnue, **DROPPED**\n{}",
                                        action, from_utf8(message.message.buffer.as_slice())?)))?,
@@ -101,10 +124,198 @@ async fn commande_sauvegarder_client<M>(gestionnaire: &GestionnaireDomaineHeberg
         Err(Error::String(format!("commande_sauvegarder_client IDMG {} expire depuis : {:?}", idmg, val_idmg.expiration)))?
     }
 
+    if let Some(reponse) = valider_services_catalogue(middleware, commande.roles.as_ref(), commande.domaines.as_ref()).await? {
+        return Ok(Some(reponse))
+    }
+    if let Some(reponse) = valider_plan_client(middleware, commande.plan_id.as_ref(), commande.roles.as_ref(), commande.domaines.as_ref()).await? {
+        return Ok(Some(reponse))
+    }
+
     // Verifier si on a une cle a sauvegarder
     if let Some(mut attachements) = message_owned.attachements {
         if let Some(cle) = attachements.remove("cle") {
-            todo!("Sauvegarder cle")
+            if let Some(reponse) = transmettre_cle_attachee(middleware, cle).await? {
+                error!("commande_sauvegarder_client Erreur sauvegarde cle pour idmg {}", idmg);
+                return Ok(Some(reponse))
+            }
+        }
+    }
+
+    sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Transmet une cle recue en attachement vers MaitreDesCles.
+/// Retourne une reponse d'erreur si la cle est refusee, None si la cle est sauvegardee.
+async fn transmettre_cle_attachee<M>(middleware: &M, cle: serde_json::Value)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages
+{
+    let message_cle: MessageMilleGrillesOwned = serde_json::from_value(cle)?;
+
+    // Valider la structure de la commande de cle avant de la transmettre
+    if let Err(e) = message_cle.deserialize::<CommandeAjouterCleDomaine>() {
+        warn!("transmettre_cle_attachee Structure de cle invalide : {:?}", e);
+        return Ok(Some(middleware.reponse_err(Some(1), None, Some("Structure de cle invalide"))?))
+    }
+
+    let message_cle: MessageMilleGrillesBufferDefault = message_cle.try_into()?;
+
+    let routage = RoutageMessageAction::builder(
+        DOMAINE_NOM_MAITREDESCLES, COMMANDE_AJOUTER_CLE_DOMAINES, vec![Securite::L1Public])
+        .timeout_blocking(5_000)
+        .build();
+    let type_message = TypeMessageOut::Commande(routage);
+
+    match middleware.emettre_message(type_message, message_cle).await {
+        Ok(inner) => match inner {
+            Some(TypeMessage::Valide(reponse)) => {
+                let reponse_ref = reponse.message.parse()?;
+                let reponse: ReponseCommande = reponse_ref.contenu()?.deserialize()?;
+                if reponse.ok == Some(true) {
+                    Ok(None)
+                } else {
+                    warn!("transmettre_cle_attachee Cle refusee par MaitreDesCles : {:?}", reponse.err);
+                    Ok(Some(middleware.reponse_err(Some(2), None, Some("Cle refusee par MaitreDesCles"))?))
+                }
+            },
+            _ => {
+                warn!("transmettre_cle_attachee Mauvais type de reponse de MaitreDesCles");
+                Ok(Some(middleware.reponse_err(Some(3), None, Some("Mauvais type de reponse de MaitreDesCles"))?))
+            }
+        },
+        Err(e) => {
+            error!("transmettre_cle_attachee Erreur sauvegarde cle : {:?}", e);
+            Ok(Some(middleware.reponse_err(Some(4), None, Some("Erreur sauvegarde cle (timeout)"))?))
+        }
+    }
+}
+
+/// Verifie que les roles et domaines sont presents dans le catalogue des services hebergeables.
+/// Retourne une reponse d'erreur qui liste les valeurs inconnues, None si toutes les valeurs sont connues.
+async fn valider_services_catalogue<M>(middleware: &M, roles: Option<&Vec<String>>, domaines: Option<&Vec<String>>)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let collection = middleware.get_collection_typed::<ServiceCatalogueRow>(constantes::COLLECTION_CATALOGUE_NOM)?;
+    let mut inconnus = Vec::new();
+    for (type_service, valeurs) in [(constantes::TYPE_SERVICE_ROLE, roles), (constantes::TYPE_SERVICE_DOMAINE, domaines)] {
+        let valeurs = match valeurs {
+            Some(inner) => inner,
+            None => continue
+        };
+        let filtre = doc!{"type": type_service, "nom": {"$in": valeurs.clone()}};
+        let mut connus = HashSet::new();
+        let mut curseur = collection.find(filtre, None).await?;
+        while let Some(row) = curseur.next().await {
+            connus.insert(row?.nom);
+        }
+        for valeur in valeurs {
+            if ! connus.contains(valeur) {
+                inconnus.push(format!("{}:{}", type_service, valeur));
+            }
+        }
+    }
+
+    if inconnus.is_empty() {
+        Ok(None)
+    } else {
+        debug!("valider_services_catalogue Services non hebergeables : {:?}", inconnus);
+        let err = format!("Roles/domaines non hebergeables : {}", inconnus.join(", "));
+        Ok(Some(middleware.reponse_err(Some(30), None, Some(err.as_str()))?))
+    }
+}
+
+/// Verifie que le plan existe et que les roles et domaines du client sont permis par le plan.
+/// Retourne une reponse d'erreur si le plan est refuse.
+async fn valider_plan_client<M>(middleware: &M, plan_id: Option<&String>, roles: Option<&Vec<String>>, domaines: Option<&Vec<String>>)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let plan_id = match plan_id {
+        Some(inner) => inner,
+        None => return Ok(None)
+    };
+    let plan = match charger_plan(middleware, plan_id).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(Some(40), None, Some("Plan inconnu"))?))
+    };
+
+    let mut refuses = Vec::new();
+    for (type_service, valeurs, permis) in [
+        (constantes::TYPE_SERVICE_ROLE, roles, plan.roles.as_ref()),
+        (constantes::TYPE_SERVICE_DOMAINE, domaines, plan.domaines.as_ref())
+    ] {
+        if let (Some(valeurs), Some(permis)) = (valeurs, permis) {
+            for valeur in valeurs {
+                if ! permis.contains(valeur) {
+                    refuses.push(format!("{}:{}", type_service, valeur));
+                }
+            }
+        }
+    }
+
+    if refuses.is_empty() {
+        Ok(None)
+    } else {
+        debug!("valider_plan_client Services non permis par le plan {} : {:?}", plan_id, refuses);
+        let err = format!("Roles/domaines non permis par le plan : {}", refuses.join(", "));
+        Ok(Some(middleware.reponse_err(Some(41), None, Some(err.as_str()))?))
+    }
+}
+
+async fn commande_maj_client<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_maj_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_maj_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+
+    // Valider structure de la commande
+    let commande: TransactionMajClient = message_owned.deserialize()?;
+
+    let filtre = doc!{"idmg": &commande.idmg};
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let client = match collection.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(Some(404), None, Some("Client inconnu"))?))
+    };
+
+    let roles = commande.roles.as_ref().and_then(|r| r.as_ref());
+    let domaines = commande.domaines.as_ref().and_then(|d| d.as_ref());
+    if let Some(reponse) = valider_services_catalogue(middleware, roles, domaines).await? {
+        return Ok(Some(reponse))
+    }
+
+    // Valider le plan avec les valeurs resultantes de la mise a jour
+    let plan_id = match commande.plan_id.as_ref() {
+        Some(inner) => inner.as_ref(),
+        None => client.plan_id.as_ref()
+    };
+    let roles = match commande.roles.as_ref() {
+        Some(inner) => inner.as_ref(),
+        None => client.roles.as_ref()
+    };
+    let domaines = match commande.domaines.as_ref() {
+        Some(inner) => inner.as_ref(),
+        None => client.domaines.as_ref()
+    };
+    if let Some(reponse) = valider_plan_client(middleware, plan_id, roles, domaines).await? {
+        return Ok(Some(reponse))
+    }
+
+    // Verifier si on a une cle a sauvegarder (nouveau data_chiffre)
+    if let Some(mut attachements) = message_owned.attachements {
+        if let Some(cle) = attachements.remove("cle") {
+            if let Some(reponse) = transmettre_cle_attachee(middleware, cle).await? {
+                error!("commande_maj_client Erreur sauvegarde cle pour idmg {}", commande.idmg);
+                return Ok(Some(reponse))
+            }
         }
     }
 
@@ -113,6 +324,62 @@ async fn commande_sauvegarder_client<M>(gestionnaire: &GestionnaireDomaineHeberg
     Ok(Some(middleware.reponse_ok(None, None)?))
 }
 
+#[derive(Deserialize)]
+struct CommandeProlongerClient {
+    idmg: String,
+    /// Duree du renouvellement. Si absent, la duree du plan du client est utilisee.
+    duree_jours: Option<i64>,
+    reference: Option<String>,
+}
+
+async fn commande_prolonger_client<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_prolonger_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_prolonger_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeProlongerClient = message_owned.deserialize()?;
+
+    let filtre = doc!{"idmg": &commande.idmg};
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let client = match collection.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(Some(404), None, Some("Client inconnu"))?))
+    };
+
+    let duree_jours = match commande.duree_jours {
+        Some(inner) => inner,
+        None => {
+            let plan = match client.plan_id.as_ref() {
+                Some(plan_id) => charger_plan(middleware, plan_id).await?,
+                None => None
+            };
+            match plan.and_then(|p| p.duree_jours) {
+                Some(inner) => inner,
+                None => return Ok(Some(middleware.reponse_err(Some(1), None, Some("Duree manquante (aucune duree de plan pour le client)"))?))
+            }
+        }
+    };
+    if duree_jours <= 0 {
+        return Ok(Some(middleware.reponse_err(Some(2), None, Some("Duree invalide"))?))
+    }
+
+    let transaction = TransactionProlongerClient {
+        idmg: commande.idmg,
+        duree_jours,
+        reference: commande.reference,
+        plan_id: client.plan_id,
+    };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_PROLONGER_CLIENT).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
 #[derive(Serialize)]
 struct EvenementConsignationHebergement {
     idmg: String,
@@ -128,13 +395,45 @@ async fn commande_ajouter_fichier<M>(gestionnaire: &GestionnaireDomaineHebergeme
 
     // Valider structure de la commande
     let commande: TransactionAjouterFichier = message_owned.deserialize()?;
+    if commande.taille_chiffre.unwrap_or(0) < 0 {
+        return Ok(Some(middleware.reponse_err(Some(60), None, Some("Taille du fichier negative"))?))
+    }
 
-    // verifier si le fichier existe deja
+    // verifier si le fichier existe deja (un fichier retire est traite comme un nouveau fichier)
     let collection = middleware.get_collection(constantes::COLLECTION_FICHIERS_NOM)?;
     let filtre = doc!{"idmg": &commande.idmg, "fuuid": &commande.fuuid};
-    if collection.find_one(filtre.clone(), None).await?.is_none() {
+    let filtre_actif = doc!{"idmg": &commande.idmg, "fuuid": &commande.fuuid, constantes::CHAMP_RETIRE: {"$ne": true}};
+    let fichier_actif = collection.count_documents(filtre_actif, None).await? > 0;
+    if ! fichier_actif {
+        // Verifier le quota du client avant d'accepter le nouveau fichier. Les quotas s'appliquent
+        // seulement aux clients configures, un fichier sans client est accepte (comportement existant).
+        let filtre_client = doc!{"idmg": &commande.idmg};
+        let collection_clients = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+        let quota = match collection_clients.find_one(filtre_client, None).await? {
+            Some(client) => charger_quota_client(middleware, &client).await?,
+            None => {
+                debug!("commande_ajouter_fichier Hebergement non configure pour {}, aucun quota", commande.idmg);
+                None
+            }
+        };
+        if let Some(quota) = quota.as_ref() {
+            let utilisation = charger_utilisation_client(middleware, commande.idmg.as_str()).await?;
+            if let Some(refus) = verifier_quota_fichier(quota, &utilisation, commande.taille_chiffre) {
+                debug!("commande_ajouter_fichier Fichier {} refuse pour idmg {} : {:?}", commande.fuuid, commande.idmg, refus);
+                return Ok(Some(middleware.reponse_err(Some(refus.code()), None, Some(refus.as_str()))?))
+            }
+        }
+
         // Sauvegarder le nouveau fichier
         sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+        // Verifier si le nouveau fichier fait franchir un seuil du quota
+        if let Some(quota) = quota.as_ref() {
+            let utilisation = charger_utilisation_client(middleware, commande.idmg.as_str()).await?;
+            if let Err(e) = verifier_seuils_quota(middleware, commande.idmg.as_str(), quota, &utilisation).await {
+                warn!("commande_ajouter_fichier Erreur verification seuils quota pour {} : {:?}", commande.idmg, e);
+            }
+        }
     } else {
         debug!("commande_ajouter_fichier Le fichier {} existe deja pour idmg {}, touch sans transaction", commande.fuuid, commande.idmg);
         let ops = doc!{
@@ -153,10 +452,790 @@ async fn commande_ajouter_fichier<M>(gestionnaire: &GestionnaireDomaineHebergeme
     // Emettre evenement de consignation du fichier pour cet hebergement
     let evenement = EvenementConsignationHebergement {idmg: commande.idmg.clone(), fuuid: commande.fuuid};
     let routage = RoutageMessageAction::builder(
-        constantes::DOMAINE_NOM, "fichierAjoute", vec![Securite::L1Public])
+        constantes::DOMAINE_NOM, constantes::EVENEMENT_FICHIER_AJOUTE, vec![Securite::L1Public])
         .partition(commande.idmg)
         .build();
     middleware.emettre_evenement(routage, &evenement).await?;
 
     Ok(Some(middleware.reponse_ok(None, None)?))
 }
+
+/// Fuuids a retirer : un seul fuuid ou une liste.
+#[derive(Deserialize)]
+struct FuuidsRetirer {
+    fuuid: Option<String>,
+    fuuids: Option<Vec<String>>,
+}
+
+impl FuuidsRetirer {
+    fn get_fuuids(self) -> Vec<String> {
+        let mut fuuids = self.fuuids.unwrap_or_default();
+        if let Some(inner) = self.fuuid {
+            fuuids.push(inner);
+        }
+        fuuids.sort();
+        fuuids.dedup();
+        fuuids
+    }
+}
+
+#[derive(Deserialize)]
+struct CommandeRetirerFichier {
+    /// Client des fichiers, requis pour un operateur.
+    idmg: Option<String>,
+    #[serde(flatten)]
+    fuuids: FuuidsRetirer,
+    /// Requete signee par l'instance du client (contenu : fuuid ou fuuids).
+    requete: Option<MessageMilleGrillesOwned>,
+}
+
+async fn commande_retirer_fichier<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_retirer_fichier Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeRetirerFichier = message_owned.deserialize()?;
+
+    let (idmg, fuuids) = match commande.requete {
+        Some(requete) => {
+            // Requete signee par l'instance du client, le idmg provient du certificat
+            let requete_client = match verifier_requete_client(middleware, requete, Some(constantes::TRANSACTION_RETIRER_FICHIER)).await? {
+                ResultatVerificationClient::Valide(inner) => inner,
+                ResultatVerificationClient::Refusee(reponse) => return Ok(Some(reponse))
+            };
+            let contenu: FuuidsRetirer = requete_client.requete.deserialize()?;
+            (requete_client.idmg, contenu.get_fuuids())
+        },
+        None => {
+            if ! verifier_acces_operateur(&message)? {
+                Err(Error::Str("commande_retirer_fichier Acces refuse (requete signee du client ou operateur requis)"))?
+            }
+            match commande.idmg {
+                Some(idmg) => (idmg, commande.fuuids.get_fuuids()),
+                None => return Ok(Some(middleware.reponse_err(Some(90), None, Some("idmg manquant"))?))
+            }
+        }
+    };
+
+    if fuuids.is_empty() {
+        return Ok(Some(middleware.reponse_err(Some(91), None, Some("fuuid ou fuuids requis"))?))
+    }
+
+    let collection_clients = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    if collection_clients.find_one(doc!{"idmg": &idmg}, None).await?.is_none() {
+        return Ok(Some(middleware.reponse_err(Some(9), None, Some("Hebergement non configure pour client"))?))
+    }
+
+    let transaction = TransactionRetirerFichier { idmg, fuuids };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_RETIRER_FICHIER).await?;
+
+    // Emettre un evenement de retrait par fichier, meme format que fichierAjoute
+    for fuuid in transaction.fuuids {
+        let evenement = EvenementConsignationHebergement {idmg: transaction.idmg.clone(), fuuid};
+        let routage = RoutageMessageAction::builder(
+            constantes::DOMAINE_NOM, constantes::EVENEMENT_FICHIER_RETIRE, vec![Securite::L1Public])
+            .partition(transaction.idmg.clone())
+            .build();
+        middleware.emettre_evenement(routage, &evenement).await?;
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Serialize)]
+struct EvenementClientSupprime {
+    idmg: String,
+}
+
+async fn commande_supprimer_client<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_supprimer_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_supprimer_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+
+    // Valider structure de la commande
+    let commande: TransactionSupprimerClient = message_owned.deserialize()?;
+
+    let collection = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    let filtre = doc!{"idmg": &commande.idmg};
+    if collection.find_one(filtre, None).await?.is_none() {
+        return Ok(Some(middleware.reponse_err(Some(404), None, Some("Client inconnu"))?))
+    }
+
+    sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+    // Emettre evenement pour permettre aux serveurs de consignation de purger les fichiers du client
+    let evenement = EvenementClientSupprime {idmg: commande.idmg.clone()};
+    let routage = RoutageMessageAction::builder(
+        constantes::DOMAINE_NOM, constantes::EVENEMENT_CLIENT_SUPPRIME, vec![Securite::L1Public])
+        .partition(commande.idmg)
+        .build();
+    middleware.emettre_evenement(routage, &evenement).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Deserialize)]
+struct CommandeDemandeHebergement {
+    /// Demande signee par la MilleGrille qui veut etre hebergee.
+    demande: MessageMilleGrillesOwned,
+}
+
+#[derive(Deserialize)]
+struct ContenuDemandeHebergement {
+    descriptif: Option<String>,
+    roles: Option<Vec<String>>,
+    domaines: Option<Vec<String>>,
+}
+
+async fn commande_demande_hebergement<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_demande_hebergement Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeDemandeHebergement = message_owned.deserialize()?;
+
+    let requete_client = match verifier_requete_client(middleware, commande.demande, Some(constantes::TRANSACTION_DEMANDE_HEBERGEMENT)).await? {
+        ResultatVerificationClient::Valide(inner) => inner,
+        ResultatVerificationClient::Refusee(reponse) => return Ok(Some(reponse))
+    };
+    let idmg = requete_client.idmg;
+    let contenu: ContenuDemandeHebergement = requete_client.requete.deserialize()?;
+
+    let filtre = doc!{"idmg": &idmg};
+    let collection_clients = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    if collection_clients.find_one(filtre, None).await?.is_some() {
+        debug!("commande_demande_hebergement Hebergement deja configure pour {}", idmg);
+        return Ok(Some(middleware.reponse_err(Some(10), None, Some("Hebergement deja configure pour client"))?))
+    }
+
+    let filtre = doc!{"idmg": &idmg, constantes::CHAMP_ETAT: constantes::ETAT_DEMANDE_ATTENTE};
+    let collection_demandes = middleware.get_collection(constantes::COLLECTION_DEMANDES_NOM)?;
+    if collection_demandes.find_one(filtre, None).await?.is_some() {
+        debug!("commande_demande_hebergement Demande deja en attente pour {}", idmg);
+        return Ok(Some(middleware.reponse_err(Some(11), None, Some("Demande deja en attente pour client"))?))
+    }
+
+    let transaction = TransactionDemandeHebergement {
+        idmg,
+        descriptif: contenu.descriptif,
+        roles: contenu.roles,
+        domaines: contenu.domaines,
+    };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_DEMANDE_HEBERGEMENT).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Charge une demande d'hebergement qui est en attente de traitement.
+async fn charger_demande_attente<M>(middleware: &M, demande_id: &str) -> Result<Option<DemandeHebergementRow>, Error>
+    where M: MongoDao
+{
+    let filtre = doc!{"demande_id": demande_id, constantes::CHAMP_ETAT: constantes::ETAT_DEMANDE_ATTENTE};
+    let collection = middleware.get_collection_typed::<DemandeHebergementRow>(constantes::COLLECTION_DEMANDES_NOM)?;
+    Ok(collection.find_one(filtre, None).await?)
+}
+
+#[derive(Deserialize)]
+struct CommandeApprouverDemande {
+    demande_id: String,
+    /// Remplace la description de la demande.
+    descriptif: Option<String>,
+    #[serde(default, with="optionepochseconds")]
+    expiration: Option<DateTime<Utc>>,
+    /// Remplace les roles de la demande.
+    roles: Option<Vec<String>>,
+    /// Remplace les domaines de la demande.
+    domaines: Option<Vec<String>>,
+    plan_id: Option<String>,
+}
+
+async fn commande_approuver_demande<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_approuver_demande Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_approuver_demande Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeApprouverDemande = message_owned.deserialize()?;
+
+    let demande = match charger_demande_attente(middleware, commande.demande_id.as_str()).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(Some(404), None, Some("Demande inconnue ou deja traitee"))?))
+    };
+
+    let filtre = doc!{"idmg": &demande.idmg};
+    let collection_clients = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    if collection_clients.find_one(filtre, None).await?.is_some() {
+        return Ok(Some(middleware.reponse_err(Some(10), None, Some("Hebergement deja configure pour client"))?))
+    }
+
+    let roles = commande.roles.or(demande.roles);
+    let domaines = commande.domaines.or(demande.domaines);
+    if let Some(reponse) = valider_services_catalogue(middleware, roles.as_ref(), domaines.as_ref()).await? {
+        return Ok(Some(reponse))
+    }
+    if let Some(reponse) = valider_plan_client(middleware, commande.plan_id.as_ref(), roles.as_ref(), domaines.as_ref()).await? {
+        return Ok(Some(reponse))
+    }
+
+    // Une seule transaction cree le client et ferme la demande
+    let transaction = TransactionApprouverDemande {
+        demande_id: commande.demande_id,
+        idmg: Some(demande.idmg),
+        descriptif: commande.descriptif.or(demande.descriptif),
+        expiration: commande.expiration,
+        roles,
+        domaines,
+        plan_id: commande.plan_id,
+    };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_APPROUVER_DEMANDE).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+async fn commande_refuser_demande<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_refuser_demande Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_refuser_demande Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: TransactionRefuserDemande = message_owned.deserialize()?;
+
+    if charger_demande_attente(middleware, commande.demande_id.as_str()).await?.is_none() {
+        return Ok(Some(middleware.reponse_err(Some(404), None, Some("Demande inconnue ou deja traitee"))?))
+    }
+
+    sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Deserialize)]
+struct CommandeCreerInvitation {
+    /// Code fourni par l'operateur. Un code est genere s'il est absent.
+    code: Option<String>,
+    plan_id: Option<String>,
+    roles: Option<Vec<String>>,
+    domaines: Option<Vec<String>>,
+    /// Expiration de l'hebergement active avec le code.
+    #[serde(default, with="optionepochseconds")]
+    expiration: Option<DateTime<Utc>>,
+    /// Le code ne peut plus etre utilise apres cette date.
+    #[serde(default, with="optionepochseconds")]
+    expiration_code: Option<DateTime<Utc>>,
+    utilisations_max: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReponseCreerInvitation {
+    ok: bool,
+    code: String,
+}
+
+async fn commande_creer_invitation<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_creer_invitation Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_creer_invitation Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeCreerInvitation = message_owned.deserialize()?;
+
+    if let Some(reponse) = valider_services_catalogue(middleware, commande.roles.as_ref(), commande.domaines.as_ref()).await? {
+        return Ok(Some(reponse))
+    }
+    if let Some(reponse) = valider_plan_client(middleware, commande.plan_id.as_ref(), commande.roles.as_ref(), commande.domaines.as_ref()).await? {
+        return Ok(Some(reponse))
+    }
+
+    let code = match commande.code {
+        Some(inner) => {
+            let filtre = doc!{"code": &inner};
+            let collection = middleware.get_collection(constantes::COLLECTION_INVITATIONS_NOM)?;
+            if collection.find_one(filtre, None).await?.is_some() {
+                return Ok(Some(middleware.reponse_err(Some(1), None, Some("Code d'invitation deja utilise"))?))
+            }
+            inner
+        },
+        None => Uuid::new_v4().to_string()
+    };
+
+    let transaction = TransactionCreerInvitation {
+        code: code.clone(),
+        plan_id: commande.plan_id,
+        roles: commande.roles,
+        domaines: commande.domaines,
+        expiration: commande.expiration,
+        expiration_code: commande.expiration_code,
+        utilisations_max: commande.utilisations_max,
+    };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_CREER_INVITATION).await?;
+
+    let reponse = ReponseCreerInvitation { ok: true, code };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct CommandeUtiliserInvitation {
+    /// Requete signee par la MilleGrille qui active son hebergement.
+    requete: MessageMilleGrillesOwned,
+}
+
+#[derive(Deserialize)]
+struct ContenuUtiliserInvitation {
+    code: String,
+    descriptif: Option<String>,
+}
+
+async fn commande_utiliser_invitation<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_utiliser_invitation Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeUtiliserInvitation = message_owned.deserialize()?;
+
+    let requete_client = match verifier_requete_client(middleware, commande.requete, Some(constantes::TRANSACTION_UTILISER_INVITATION)).await? {
+        ResultatVerificationClient::Valide(inner) => inner,
+        ResultatVerificationClient::Refusee(reponse) => return Ok(Some(reponse))
+    };
+    let idmg = requete_client.idmg;
+    let contenu: ContenuUtiliserInvitation = requete_client.requete.deserialize()?;
+
+    let filtre = doc!{"code": &contenu.code};
+    let collection_invitations = middleware.get_collection_typed::<InvitationRow>(constantes::COLLECTION_INVITATIONS_NOM)?;
+    let invitation = match collection_invitations.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => {
+            debug!("commande_utiliser_invitation Code {} inconnu", contenu.code);
+            return Ok(Some(middleware.reponse_err(Some(20), None, Some("Code d'invitation invalide"))?))
+        }
+    };
+
+    if let Some(expiration_code) = invitation.expiration_code.as_ref() {
+        if *expiration_code < Utc::now() {
+            return Ok(Some(middleware.reponse_err(Some(21), None, Some("Code d'invitation expire"))?))
+        }
+    }
+    if let Some(utilisations_max) = invitation.utilisations_max {
+        if invitation.utilisations.unwrap_or_else(|| 0) >= utilisations_max {
+            return Ok(Some(middleware.reponse_err(Some(22), None, Some("Code d'invitation epuise"))?))
+        }
+    }
+
+    let filtre = doc!{"idmg": &idmg};
+    let collection_clients = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    if collection_clients.find_one(filtre, None).await?.is_some() {
+        return Ok(Some(middleware.reponse_err(Some(10), None, Some("Hebergement deja configure pour client"))?))
+    }
+
+    let transaction_invitation = TransactionUtiliserInvitation { code: invitation.code, idmg: idmg.clone() };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction_invitation, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_UTILISER_INVITATION).await?;
+
+    // La transaction re-verifie la limite d'utilisations et l'expiration du code (deux utilisations
+    // concurrentes peuvent passer les verifications precedentes). Confirmer que l'utilisation a ete comptee.
+    let filtre = doc!{"code": &transaction_invitation.code, "idmgs": &idmg};
+    if collection_invitations.find_one(filtre, None).await?.is_none() {
+        debug!("commande_utiliser_invitation Code {} epuise ou expire lors de l'utilisation", transaction_invitation.code);
+        return Ok(Some(middleware.reponse_err(Some(22), None, Some("Code d'invitation epuise"))?))
+    }
+
+    // Activer l'hebergement avec la transaction standard
+    let transaction_client = TransactionSauvegarderClient {
+        idmg,
+        descriptif: contenu.descriptif,
+        expiration: invitation.expiration,
+        roles: invitation.roles,
+        domaines: invitation.domaines,
+        data_chiffre: None,
+        actif: Some(true),
+        suspendu: None,
+        quota: None,
+        plan_id: invitation.plan_id,
+        prepaye: None,
+    };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction_client, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_SAUVEGARDER_CLIENT).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+async fn commande_ajouter_service_catalogue<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_ajouter_service_catalogue Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_ajouter_service_catalogue Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: ServiceCatalogueRow = message_owned.deserialize()?;
+    match commande.type_service.as_str() {
+        constantes::TYPE_SERVICE_ROLE | constantes::TYPE_SERVICE_DOMAINE => (),
+        _ => return Ok(Some(middleware.reponse_err(Some(1), None, Some("Type de service doit etre role ou domaine"))?))
+    }
+    if commande.nom.is_empty() {
+        return Ok(Some(middleware.reponse_err(Some(2), None, Some("Nom de service manquant"))?))
+    }
+
+    sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+async fn commande_retirer_service_catalogue<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_retirer_service_catalogue Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_retirer_service_catalogue Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: TransactionRetirerServiceCatalogue = message_owned.deserialize()?;
+
+    let filtre = doc!{"type": &commande.type_service, "nom": &commande.nom};
+    let collection = middleware.get_collection(constantes::COLLECTION_CATALOGUE_NOM)?;
+    if collection.find_one(filtre, None).await?.is_none() {
+        return Ok(Some(middleware.reponse_err(Some(404), None, Some("Service inconnu"))?))
+    }
+
+    sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+async fn commande_sauvegarder_plan<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_sauvegarder_plan Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_sauvegarder_plan Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: PlanHebergementRow = message_owned.deserialize()?;
+    if commande.plan_id.is_empty() {
+        return Ok(Some(middleware.reponse_err(Some(1), None, Some("plan_id manquant"))?))
+    }
+
+    if let Some(reponse) = valider_services_catalogue(middleware, commande.roles.as_ref(), commande.domaines.as_ref()).await? {
+        return Ok(Some(reponse))
+    }
+
+    sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Deserialize)]
+struct TransfertClient {
+    idmg: String,
+    octets_upload: i64,
+    octets_download: i64,
+}
+
+#[derive(Deserialize)]
+struct CommandeRapporterTransfert {
+    /// Date dans la journee rapportee (UTC).
+    #[serde(with = "epochseconds")]
+    jour: DateTime<Utc>,
+    /// Totaux cumulatifs de la journee pour chaque client. Un nouveau rapport pour la meme journee
+    /// remplace le precedent.
+    transferts: Vec<TransfertClient>,
+}
+
+/// Rapport de transfert (upload/download) par client d'une instance de consignation.
+async fn commande_rapporter_transfert<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("commande_rapporter_transfert Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! message.certificat.verifier_roles(vec![RolesCertificats::Fichiers])? {
+        Err(Error::Str("commande_rapporter_transfert Acces refuse (certificat de consignation requis)"))?
+    }
+    let instance_id = message.certificat.get_common_name()?;
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeRapporterTransfert = message_owned.deserialize()?;
+    let jour = debut_jour(&commande.jour);
+
+    if commande.transferts.iter().any(|t| t.octets_upload < 0 || t.octets_download < 0) {
+        return Ok(Some(middleware.reponse_err(Some(60), None, Some("Nombre d'octets negatif"))?))
+    }
+
+    let collection = middleware.get_collection(constantes::COLLECTION_TRANSFERTS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    for transfert in commande.transferts {
+        let row = TransfertJourRow {
+            idmg: transfert.idmg,
+            jour,
+            instance_id: instance_id.clone(),
+            octets_upload: transfert.octets_upload,
+            octets_download: transfert.octets_download,
+        };
+        let filtre = doc!{"idmg": &row.idmg, "jour": jour, "instance_id": &row.instance_id};
+        let ops = doc!{
+            "$set": convertir_to_bson(&row)?,
+            "$setOnInsert": {CommonConstantes::CHAMP_CREATION: Utc::now()},
+            "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true},
+        };
+        collection.update_one(filtre, ops, options.clone()).await?;
+    }
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Deserialize)]
+struct CommandeGenererReleves {
+    annee: i32,
+    /// Mois de 1 a 12.
+    mois: u32,
+    /// Generer le releve d'un seul client. Tous les clients par defaut.
+    idmg: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReponseGenererReleves {
+    ok: bool,
+    err: Option<String>,
+    /// Nombre de releves generes (brouillons).
+    releves: usize,
+    /// Nombre de releves deja finalises, non modifies.
+    finalises: usize,
+}
+
+/// Genere (ou regenere) les brouillons de releves mensuels. Les releves finalises ne sont pas modifies.
+async fn commande_generer_releves<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("commande_generer_releves Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_generer_releves Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeGenererReleves = message_owned.deserialize()?;
+
+    let filtre = match commande.idmg.as_ref() {
+        Some(idmg) => doc!{"idmg": idmg},
+        None => doc!{}
+    };
+
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection_releves = middleware.get_collection(constantes::COLLECTION_RELEVES_NOM)?;
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut releves = 0;
+    let mut finalises = 0;
+    while curseur.advance().await? {
+        let client = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("commande_generer_releves Erreur mapping client, skip : {:?}", e);
+                continue
+            }
+        };
+        let calcul = calculer_releve(middleware, &client, commande.annee, commande.mois).await?;
+
+        let filtre_releve = doc!{"idmg": &calcul.idmg, "mois": &calcul.mois};
+        let filtre_finalise = doc!{"idmg": &calcul.idmg, "mois": &calcul.mois, constantes::CHAMP_ETAT: constantes::ETAT_RELEVE_FINALISE};
+        if collection_releves.find_one(filtre_finalise, None).await?.is_some() {
+            debug!("commande_generer_releves Releve {} {} deja finalise", calcul.idmg, calcul.mois);
+            finalises += 1;
+            continue
+        }
+
+        let releve = ReleveRow { calcul, etat: constantes::ETAT_RELEVE_BROUILLON.to_string() };
+        let ops = doc!{
+            "$setOnInsert": {CommonConstantes::CHAMP_CREATION: Utc::now()},
+            "$set": convertir_to_bson(&releve)?,
+            "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+        };
+        collection_releves.update_one(filtre_releve, ops, options.clone()).await?;
+        releves += 1;
+    }
+
+    let reponse = ReponseGenererReleves { ok: true, err: None, releves, finalises };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct CommandeFinaliserReleve {
+    idmg: String,
+    annee: i32,
+    /// Mois de 1 a 12.
+    mois: u32,
+}
+
+async fn commande_finaliser_releve<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_finaliser_releve Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_finaliser_releve Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeFinaliserReleve = message_owned.deserialize()?;
+
+    let filtre = doc!{"idmg": &commande.idmg};
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let client = match collection.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(Some(9), None, Some("Hebergement non configure pour client"))?))
+    };
+
+    // Recalculer le releve au moment de la finalisation, le resultat est conserve dans la transaction
+    let calcul = calculer_releve(middleware, &client, commande.annee, commande.mois).await?;
+
+    let filtre_finalise = doc!{"idmg": &calcul.idmg, "mois": &calcul.mois, constantes::CHAMP_ETAT: constantes::ETAT_RELEVE_FINALISE};
+    let collection_releves = middleware.get_collection(constantes::COLLECTION_RELEVES_NOM)?;
+    if collection_releves.find_one(filtre_finalise, None).await?.is_some() {
+        return Ok(Some(middleware.reponse_err(Some(70), None, Some("Releve deja finalise"))?))
+    }
+
+    // Client prepaye : le releve est debite au grand livre, moins la consommation journaliere deja debitee
+    let ajustement_grand_livre = match client.prepaye {
+        Some(true) => Some(calcul.montant_total - charger_debits_mois(middleware, calcul.idmg.as_str(), calcul.mois.as_str()).await?),
+        _ => None
+    };
+
+    let transaction = TransactionFinaliserReleve { calcul, ajustement_grand_livre };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_FINALISER_RELEVE).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+/// Verifie que le client existe pour une ecriture au grand livre.
+async fn verifier_client_grand_livre<M>(middleware: &M, idmg: &str) -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let collection = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    if collection.find_one(doc!{"idmg": idmg}, None).await?.is_none() {
+        return Ok(Some(middleware.reponse_err(Some(9), None, Some("Hebergement non configure pour client"))?))
+    }
+    Ok(None)
+}
+
+/// Credit au grand livre d'un client (e.g. paiement recu).
+async fn commande_crediter_client<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_crediter_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_crediter_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: TransactionEcritureGrandLivre = message_owned.deserialize()?;
+    if commande.montant <= 0 {
+        return Ok(Some(middleware.reponse_err(Some(80), None, Some("Le montant doit etre positif"))?))
+    }
+    if let Some(reponse) = verifier_client_grand_livre(middleware, commande.idmg.as_str()).await? {
+        return Ok(Some(reponse))
+    }
+
+    sauvegarder_traiter_transaction_v2(middleware, message, gestionnaire).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
+
+#[derive(Deserialize)]
+struct CommandeDebiterClient {
+    idmg: String,
+    /// Montant en cents. Si absent, le montant du releve finalise du mois est utilise.
+    montant: Option<i64>,
+    reference: Option<String>,
+    /// Mois du releve debite, format YYYY-MM.
+    mois: Option<String>,
+}
+
+/// Debit au grand livre d'un client : releve mensuel ou consommation.
+async fn commande_debiter_client<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("commande_debiter_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("commande_debiter_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_owned = message.message.parse_to_owned()?;
+    let commande: CommandeDebiterClient = message_owned.deserialize()?;
+    if let Some(reponse) = verifier_client_grand_livre(middleware, commande.idmg.as_str()).await? {
+        return Ok(Some(reponse))
+    }
+
+    let montant = match commande.mois.as_ref() {
+        Some(mois) => {
+            // Un releve n'est debite qu'une seule fois (ajustement de finalisation d'un client prepaye ou debit manuel)
+            let ecritures = charger_ecritures_mois(middleware, commande.idmg.as_str(), mois.as_str()).await?;
+            if releve_deja_debite(&ecritures, mois.as_str()) {
+                return Ok(Some(middleware.reponse_err(Some(81), None, Some("Releve deja debite"))?))
+            }
+            match commande.montant {
+                Some(inner) => inner,
+                None => {
+                    // Le debit complete la consommation journaliere deja debitee pour le mois
+                    let filtre_releve = doc!{"idmg": &commande.idmg, "mois": mois, constantes::CHAMP_ETAT: constantes::ETAT_RELEVE_FINALISE};
+                    let collection_releves = middleware.get_collection_typed::<ReleveRow>(constantes::COLLECTION_RELEVES_NOM)?;
+                    match collection_releves.find_one(filtre_releve, None).await? {
+                        Some(inner) => inner.calcul.montant_total - charger_debits_mois(middleware, commande.idmg.as_str(), mois.as_str()).await?,
+                        None => return Ok(Some(middleware.reponse_err(Some(82), None, Some("Releve finalise introuvable"))?))
+                    }
+                }
+            }
+        },
+        None => match commande.montant {
+            Some(inner) => inner,
+            None => return Ok(Some(middleware.reponse_err(Some(1), None, Some("montant ou mois requis"))?))
+        }
+    };
+    if montant <= 0 {
+        return Ok(Some(middleware.reponse_err(Some(80), None, Some("Le montant doit etre positif"))?))
+    }
+
+    let transaction = TransactionEcritureGrandLivre {
+        idmg: commande.idmg,
+        montant,
+        reference: commande.reference,
+        mois: commande.mois,
+        jour: None,
+    };
+    sauvegarder_traiter_transaction_serializable_v2(
+        middleware, &transaction, gestionnaire, DOMAINE_NOM, constantes::TRANSACTION_DEBITER_CLIENT).await?;
+
+    Ok(Some(middleware.reponse_ok(None, None)?))
+}
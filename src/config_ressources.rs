@@ -1,3 +1,7 @@
+use std::env;
+
+use log::warn;
+use millegrilles_common_rust::chrono;
 use millegrilles_common_rust::configuration::ConfigMessages;
 use millegrilles_common_rust::constantes::{DEFAULT_Q_TTL, Securite};
 use millegrilles_common_rust::error::Error;
@@ -14,10 +18,41 @@ pub fn preparer_queues() -> Vec<QueueType> {
     // Requetes
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_LISTE_CLIENTS), exchange: Securite::L3Protege});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_TOKEN_JWT), exchange: Securite::L1Public});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_CLIENT), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_STATUT_HEBERGEMENT), exchange: Securite::L1Public});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_LISTE_DEMANDES), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_HISTORIQUE_CLIENT), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_CATALOGUE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_LISTE_PLANS), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_UTILISATION_CLIENT), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_UTILISATION_CLIENTS), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_TRANSFERTS_MENSUELS), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_HISTORIQUE_UTILISATION), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_EXPORTER_RELEVES), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("requete.{}.{}", constantes::DOMAINE_NOM, constantes::REQUETE_GRAND_LIVRE_CLIENT), exchange: Securite::L3Protege});
 
     // Commandes
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_SAUVEGARDER_CLIENT), exchange: Securite::L3Protege});
     rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_AJOUTER_FICHIER), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_SUPPRIMER_CLIENT), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_MAJ_CLIENT), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_PROLONGER_CLIENT), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_DEMANDE_HEBERGEMENT), exchange: Securite::L1Public});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_APPROUVER_DEMANDE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_REFUSER_DEMANDE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_CREER_INVITATION), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_UTILISER_INVITATION), exchange: Securite::L1Public});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_AJOUTER_SERVICE_CATALOGUE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_RETIRER_SERVICE_CATALOGUE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_SAUVEGARDER_PLAN), exchange: Securite::L3Protege});
+    // retirerFichier : instance du client (requete signee, 1.public) ou operateur (3.protege)
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_RETIRER_FICHIER), exchange: Securite::L1Public});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_RETIRER_FICHIER), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::COMMANDE_RAPPORTER_TRANSFERT), exchange: Securite::L2Prive});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::COMMANDE_GENERER_RELEVES), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_FINALISER_RELEVE), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_CREDITER_CLIENT), exchange: Securite::L3Protege});
+    rk_volatils.push(ConfigRoutingExchange {routing_key: format!("commande.{}.{}", constantes::DOMAINE_NOM, constantes::TRANSACTION_DEBITER_CLIENT), exchange: Securite::L3Protege});
 
     // Evenements
     // rk_volatils.push(ConfigRoutingExchange {routing_key: format!("evenement.{}.{}", DOMAINE_FICHIERS, EVENEMENT_FICHIERS_SYNCPRET), exchange: Securite::L3Protege});
@@ -41,9 +76,275 @@ pub fn preparer_queues() -> Vec<QueueType> {
     queues
 }
 
+/// Periode de grace (acces en lecture seule) apres l'expiration d'un client.
+pub fn get_periode_grace() -> chrono::Duration {
+    let jours = match env::var(constantes::ENV_PERIODE_GRACE_JOURS) {
+        Ok(inner) => match inner.parse::<i64>() {
+            Ok(inner) => inner,
+            Err(e) => {
+                warn!("get_periode_grace Valeur {} invalide ({:?}), utiliser defaut", constantes::ENV_PERIODE_GRACE_JOURS, e);
+                constantes::CONST_PERIODE_GRACE_JOURS
+            }
+        },
+        Err(_) => constantes::CONST_PERIODE_GRACE_JOURS
+    };
+    chrono::Duration::days(jours)
+}
+
+/// Liste d'entiers separes par virgules lue de la variable d'environnement nom, triee et sans
+/// doublons. La valeur par defaut est utilisee si la variable est absente ou invalide.
+fn parse_liste_env(nom: &str, defaut: &[i64]) -> Vec<i64> {
+    let mut valeurs = match env::var(nom) {
+        Ok(inner) => {
+            let valeurs: Result<Vec<i64>, _> = inner.split(',').map(|v| v.trim().parse::<i64>()).collect();
+            match valeurs {
+                Ok(inner) => inner,
+                Err(e) => {
+                    warn!("parse_liste_env Valeur {} invalide ({:?}), utiliser defaut", nom, e);
+                    defaut.to_vec()
+                }
+            }
+        },
+        Err(_) => defaut.to_vec()
+    };
+    valeurs.sort();
+    valeurs.dedup();
+    valeurs
+}
+
+/// Delais (en jours) avant l'expiration d'un client pour emettre un rappel, en ordre croissant.
+/// Format de la variable d'environnement : liste separee par virgules, e.g. 30,7,1.
+pub fn get_rappels_expiration() -> Vec<i64> {
+    parse_liste_env(constantes::ENV_RAPPELS_EXPIRATION, &constantes::CONST_RAPPELS_EXPIRATION)
+}
+
+/// Seuils d'avertissement du quota (pourcentage utilise), en ordre croissant. Le depassement
+/// (100%) est toujours signale. Format de la variable d'environnement : e.g. 80,95.
+pub fn get_seuils_quota() -> Vec<i64> {
+    let mut seuils = parse_liste_env(constantes::ENV_SEUILS_QUOTA, &constantes::CONST_SEUILS_QUOTA);
+    seuils.retain(|s| *s > 0 && *s < 100);
+    seuils
+}
+
 pub async fn preparer_index_mongodb_hebergement<M>(middleware: &M, _gestionnaire: &GestionnaireDomaineHebergement) -> Result<(), Error>
     where M: MongoDao + ConfigMessages
 {
+    // Index clients
+    let options_clients_idmg = IndexOptions {
+        nom_index: Some(String::from("idmg")),
+        unique: true,
+    };
+    let champs_index_clients_idmg = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_CLIENTS_NOM,
+        champs_index_clients_idmg,
+        Some(options_clients_idmg)
+    ).await?;
+
+    let options_clients_expiration = IndexOptions {
+        nom_index: Some(String::from("expiration")),
+        unique: false,
+    };
+    let champs_index_clients_expiration = vec!(
+        ChampIndex {nom_champ: String::from("expiration"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_CLIENTS_NOM,
+        champs_index_clients_expiration,
+        Some(options_clients_expiration)
+    ).await?;
+
+    // Index notifications (evenements emis une seule fois)
+    let options_notifications = IndexOptions {
+        nom_index: Some(String::from("idmg_type_cle")),
+        unique: true,
+    };
+    let champs_index_notifications = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+        ChampIndex {nom_champ: String::from("type"), direction: 1},
+        ChampIndex {nom_champ: String::from("cle"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_NOTIFICATIONS_NOM,
+        champs_index_notifications,
+        Some(options_notifications)
+    ).await?;
+
+    // Index demandes d'hebergement
+    let options_demandes = IndexOptions {
+        nom_index: Some(String::from("demande_id")),
+        unique: true,
+    };
+    let champs_index_demandes = vec!(
+        ChampIndex {nom_champ: String::from("demande_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_DEMANDES_NOM,
+        champs_index_demandes,
+        Some(options_demandes)
+    ).await?;
+
+    // Index codes d'invitation
+    let options_invitations = IndexOptions {
+        nom_index: Some(String::from("code")),
+        unique: true,
+    };
+    let champs_index_invitations = vec!(
+        ChampIndex {nom_champ: String::from("code"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_INVITATIONS_NOM,
+        champs_index_invitations,
+        Some(options_invitations)
+    ).await?;
+
+    // Index catalogue des services hebergeables
+    let options_catalogue = IndexOptions {
+        nom_index: Some(String::from("type_nom")),
+        unique: true,
+    };
+    let champs_index_catalogue = vec!(
+        ChampIndex {nom_champ: String::from("type"), direction: 1},
+        ChampIndex {nom_champ: String::from("nom"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_CATALOGUE_NOM,
+        champs_index_catalogue,
+        Some(options_catalogue)
+    ).await?;
+
+    // Index plans d'hebergement
+    let options_plans = IndexOptions {
+        nom_index: Some(String::from("plan_id")),
+        unique: true,
+    };
+    let champs_index_plans = vec!(
+        ChampIndex {nom_champ: String::from("plan_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_PLANS_NOM,
+        champs_index_plans,
+        Some(options_plans)
+    ).await?;
+
+    // Index renouvellements
+    let options_renouvellements = IndexOptions {
+        nom_index: Some(String::from("transaction_id")),
+        unique: true,
+    };
+    let champs_index_renouvellements = vec!(
+        ChampIndex {nom_champ: String::from("transaction_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_RENOUVELLEMENTS_NOM,
+        champs_index_renouvellements,
+        Some(options_renouvellements)
+    ).await?;
+
+    let options_renouvellements_idmg = IndexOptions {
+        nom_index: Some(String::from("idmg_date")),
+        unique: false,
+    };
+    let champs_index_renouvellements_idmg = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+        ChampIndex {nom_champ: String::from("date_renouvellement"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_RENOUVELLEMENTS_NOM,
+        champs_index_renouvellements_idmg,
+        Some(options_renouvellements_idmg)
+    ).await?;
+
+    // Index transferts journaliers
+    let options_transferts = IndexOptions {
+        nom_index: Some(String::from("idmg_jour_instance")),
+        unique: true,
+    };
+    let champs_index_transferts = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+        ChampIndex {nom_champ: String::from("jour"), direction: 1},
+        ChampIndex {nom_champ: String::from("instance_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_TRANSFERTS_NOM,
+        champs_index_transferts,
+        Some(options_transferts)
+    ).await?;
+
+    // Index utilisation journaliere
+    let options_utilisation_journaliere = IndexOptions {
+        nom_index: Some(String::from("idmg_jour")),
+        unique: true,
+    };
+    let champs_index_utilisation_journaliere = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+        ChampIndex {nom_champ: String::from("jour"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_UTILISATION_JOURNALIERE_NOM,
+        champs_index_utilisation_journaliere,
+        Some(options_utilisation_journaliere)
+    ).await?;
+
+    // Index releves mensuels
+    let options_releves = IndexOptions {
+        nom_index: Some(String::from("idmg_mois")),
+        unique: true,
+    };
+    let champs_index_releves = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+        ChampIndex {nom_champ: String::from("mois"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_RELEVES_NOM,
+        champs_index_releves,
+        Some(options_releves)
+    ).await?;
+
+    // Index grand livre
+    let options_grand_livre = IndexOptions {
+        nom_index: Some(String::from("transaction_id")),
+        unique: true,
+    };
+    let champs_index_grand_livre = vec!(
+        ChampIndex {nom_champ: String::from("transaction_id"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_GRAND_LIVRE_NOM,
+        champs_index_grand_livre,
+        Some(options_grand_livre)
+    ).await?;
+
+    let options_grand_livre_idmg = IndexOptions {
+        nom_index: Some(String::from("idmg_date")),
+        unique: false,
+    };
+    let champs_index_grand_livre_idmg = vec!(
+        ChampIndex {nom_champ: String::from("idmg"), direction: 1},
+        ChampIndex {nom_champ: String::from("date"), direction: 1},
+    );
+    middleware.create_index(
+        middleware,
+        constantes::COLLECTION_GRAND_LIVRE_NOM,
+        champs_index_grand_livre_idmg,
+        Some(options_grand_livre_idmg)
+    ).await?;
+
     // let options_usagers = IndexOptions {
     //     nom_index: Some(String::from("user_id")),
     //     unique: true,
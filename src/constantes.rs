@@ -3,18 +3,95 @@ pub const COLLECTION_NOM: &str = DOMAINE_NOM;
 
 pub const COLLECTION_CLIENTS_NOM: &str = "Hebergement/clients";
 pub const COLLECTION_FICHIERS_NOM: &str = "Hebergement/fichiers";
+pub const COLLECTION_CLIENTS_ARCHIVES_NOM: &str = "Hebergement/clientsArchives";
+pub const COLLECTION_NOTIFICATIONS_NOM: &str = "Hebergement/notifications";
+pub const COLLECTION_DEMANDES_NOM: &str = "Hebergement/demandes";
+pub const COLLECTION_INVITATIONS_NOM: &str = "Hebergement/invitations";
+pub const COLLECTION_CATALOGUE_NOM: &str = "Hebergement/catalogue";
+pub const COLLECTION_PLANS_NOM: &str = "Hebergement/plans";
+pub const COLLECTION_RENOUVELLEMENTS_NOM: &str = "Hebergement/renouvellements";
+pub const COLLECTION_TRANSFERTS_NOM: &str = "Hebergement/transferts";
+pub const COLLECTION_UTILISATION_JOURNALIERE_NOM: &str = "Hebergement/utilisationJournaliere";
+pub const COLLECTION_RELEVES_NOM: &str = "Hebergement/releves";
+pub const COLLECTION_GRAND_LIVRE_NOM: &str = "Hebergement/grandLivre";
 
 pub const QUEUE_VOLATILS_NOM: &str = "Hebergement/volatils";
 pub const QUEUE_TRIGGERS_NOM: &str = "Hebergement/triggers";
 pub const REQUETE_LISTE_CLIENTS: &str = "getListeClients";
 pub const REQUETE_TOKEN_JWT: &str = "getTokenJwt";
+pub const REQUETE_CLIENT: &str = "getClient";
+pub const REQUETE_STATUT_HEBERGEMENT: &str = "getStatutHebergement";
+pub const REQUETE_LISTE_DEMANDES: &str = "getListeDemandes";
+pub const REQUETE_HISTORIQUE_CLIENT: &str = "getHistoriqueClient";
+pub const REQUETE_CATALOGUE: &str = "getCatalogue";
+pub const REQUETE_LISTE_PLANS: &str = "getListePlans";
+pub const REQUETE_UTILISATION_CLIENT: &str = "getUtilisationClient";
+pub const REQUETE_UTILISATION_CLIENTS: &str = "getUtilisationClients";
+pub const REQUETE_TRANSFERTS_MENSUELS: &str = "getTransfertsMensuels";
+pub const REQUETE_HISTORIQUE_UTILISATION: &str = "getHistoriqueUtilisation";
+pub const REQUETE_EXPORTER_RELEVES: &str = "exporterReleves";
+pub const REQUETE_GRAND_LIVRE_CLIENT: &str = "getGrandLivreClient";
 
 //pub const COMMANDE_SAUVEGARDER_CLIENT: &str = "sauvegarderClient";
 pub const TRANSACTION_SAUVEGARDER_CLIENT: &str = "sauvegarderClient";
 pub const TRANSACTION_AJOUTER_FICHIER: &str = "ajouterFichier";
+pub const TRANSACTION_SUPPRIMER_CLIENT: &str = "supprimerClient";
+pub const TRANSACTION_MAJ_CLIENT: &str = "majClient";
+pub const TRANSACTION_PROLONGER_CLIENT: &str = "prolongerClient";
+pub const TRANSACTION_DEMANDE_HEBERGEMENT: &str = "demandeHebergement";
+pub const TRANSACTION_APPROUVER_DEMANDE: &str = "approuverDemande";
+pub const TRANSACTION_REFUSER_DEMANDE: &str = "refuserDemande";
+pub const TRANSACTION_CREER_INVITATION: &str = "creerInvitation";
+pub const TRANSACTION_UTILISER_INVITATION: &str = "utiliserInvitation";
+pub const TRANSACTION_AJOUTER_SERVICE_CATALOGUE: &str = "ajouterServiceCatalogue";
+pub const TRANSACTION_RETIRER_SERVICE_CATALOGUE: &str = "retirerServiceCatalogue";
+pub const TRANSACTION_SAUVEGARDER_PLAN: &str = "sauvegarderPlan";
+pub const TRANSACTION_RETIRER_FICHIER: &str = "retirerFichier";
+pub const COMMANDE_RAPPORTER_TRANSFERT: &str = "rapporterTransfert";
+pub const COMMANDE_GENERER_RELEVES: &str = "genererReleves";
+pub const TRANSACTION_FINALISER_RELEVE: &str = "finaliserReleve";
+pub const TRANSACTION_CREDITER_CLIENT: &str = "crediterClient";
+pub const TRANSACTION_DEBITER_CLIENT: &str = "debiterClient";
 
 // pub const EVENEMENT_NOUVEAU_MESSAGE: &str = "nouveauMessage";
+pub const EVENEMENT_CLIENT_SUPPRIME: &str = "clientSupprime";
+pub const EVENEMENT_FICHIER_AJOUTE: &str = "fichierAjoute";
+pub const EVENEMENT_FICHIER_RETIRE: &str = "fichierRetire";
+pub const EVENEMENT_CLIENT_EXPIRATION_PROCHE: &str = "clientExpirationProche";
+pub const EVENEMENT_CLIENT_EXPIRE: &str = "clientExpire";
+pub const EVENEMENT_CLIENT_FIN_GRACE: &str = "clientFinGrace";
+pub const EVENEMENT_QUOTA_AVERTISSEMENT: &str = "quotaAvertissement";
+pub const EVENEMENT_QUOTA_DEPASSE: &str = "quotaDepasse";
 
 pub const CHAMP_DATE_PRESENCE: &str = "date_presence";
 pub const CHAMP_DATE_SYNC: &str = "date_sync";
-pub const CHAMP_SYNC_EN_COURS: &str = "sync_en_cours";
\ No newline at end of file
+pub const CHAMP_SYNC_EN_COURS: &str = "sync_en_cours";
+pub const CHAMP_TAILLE_CHIFFRE: &str = "taille_chiffre";
+pub const CHAMP_DOMAINE: &str = "domaine";
+pub const CHAMP_CLASSE: &str = "classe";
+pub const CHAMP_ETAT: &str = "etat";
+pub const CHAMP_DATE_TRAITEMENT: &str = "date_traitement";
+pub const CHAMP_RETIRE: &str = "retire";
+pub const CHAMP_DATE_RETRAIT: &str = "date_retrait";
+
+pub const TYPE_SERVICE_ROLE: &str = "role";
+pub const TYPE_SERVICE_DOMAINE: &str = "domaine";
+
+pub const ETAT_DEMANDE_ATTENTE: &str = "attente";
+pub const ETAT_DEMANDE_APPROUVEE: &str = "approuvee";
+pub const ETAT_DEMANDE_REFUSEE: &str = "refusee";
+pub const ETAT_RELEVE_BROUILLON: &str = "brouillon";
+pub const ETAT_RELEVE_FINALISE: &str = "finalise";
+pub const TYPE_ECRITURE_CREDIT: &str = "credit";
+pub const TYPE_ECRITURE_DEBIT: &str = "debit";
+pub const REFERENCE_ECRITURE_CONSOMMATION: &str = "consommation";
+pub const REFERENCE_ECRITURE_RELEVE: &str = "releve";
+
+pub const ENV_PERIODE_GRACE_JOURS: &str = "MG_HEBERGEMENT_PERIODE_GRACE_JOURS";
+pub const CONST_PERIODE_GRACE_JOURS: i64 = 14;
+/// Marge apres la fin de la periode de grace pendant laquelle clientFinGrace peut encore etre emis.
+pub const CONST_MARGE_FIN_GRACE_JOURS: i64 = 7;
+pub const ENV_RAPPELS_EXPIRATION: &str = "MG_HEBERGEMENT_RAPPELS_EXPIRATION";
+pub const CONST_RAPPELS_EXPIRATION: [i64; 3] = [30, 7, 1];
+pub const ENV_SEUILS_QUOTA: &str = "MG_HEBERGEMENT_SEUILS_QUOTA";
+pub const CONST_SEUILS_QUOTA: [i64; 2] = [80, 95];
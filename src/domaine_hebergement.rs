@@ -23,6 +23,7 @@ use crate::commandes::consommer_commande;
 
 use crate::constantes as Constantes;
 use crate::config_ressources::{preparer_index_mongodb_hebergement, preparer_queues};
+use crate::entretien::{entretien_consommation_prepayee, entretien_expirations, entretien_quotas, entretien_utilisation_journaliere, migrer_expiration_clients};
 use crate::evenements::consommer_evenement;
 use crate::requetes::consommer_requete;
 use crate::transactions::aiguillage_transaction;
@@ -86,6 +87,8 @@ async fn initialiser<M>(middleware: &'static M) -> Result<(&'static Gestionnaire
     // Preparer des ressources additionnelles
     preparer_index_mongodb_hebergement(middleware, gestionnaire).await
         .expect("preparer_index_mongodb_messages");
+    migrer_expiration_clients(middleware).await
+        .expect("migrer_expiration_clients");
 
     Ok((gestionnaire, futures))
 }
@@ -164,11 +167,17 @@ impl ConsommateurMessagesBus for GestionnaireDomaineHebergement {
     }
 }
 
-async fn thread_entretien<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M)
+async fn thread_entretien<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M)
     where M: Middleware
 {
     let mut prochain_chargement_certificats_maitredescles = Utc::now();
     let intervalle_chargement_certificats_maitredescles = chrono::Duration::minutes(5);
+    let mut prochain_entretien_expirations = Utc::now();
+    let intervalle_entretien_expirations = chrono::Duration::minutes(15);
+    let mut prochain_entretien_quotas = Utc::now();
+    let intervalle_entretien_quotas = chrono::Duration::minutes(15);
+    let mut prochain_entretien_utilisation = Utc::now();
+    let intervalle_entretien_utilisation = chrono::Duration::hours(1);
 
     // Attendre 5 secondes pour init bus
     tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
@@ -188,6 +197,40 @@ async fn thread_entretien<M>(_gestionnaire: &GestionnaireDomaineHebergement, mid
 
         }
 
+        if prochain_entretien_expirations < maintenant {
+            match entretien_expirations(middleware).await {
+                Ok(()) => {
+                    prochain_entretien_expirations = maintenant + intervalle_entretien_expirations;
+                    debug!("domaines_core.entretien Prochain entretien expirations: {:?}", prochain_entretien_expirations);
+                },
+                Err(e) => warn!("domaines_core.entretien Erreur entretien expirations : {:?}", e)
+            }
+        }
+
+        if prochain_entretien_quotas < maintenant {
+            match entretien_quotas(middleware).await {
+                Ok(()) => {
+                    prochain_entretien_quotas = maintenant + intervalle_entretien_quotas;
+                    debug!("domaines_core.entretien Prochain entretien quotas: {:?}", prochain_entretien_quotas);
+                },
+                Err(e) => warn!("domaines_core.entretien Erreur entretien quotas : {:?}", e)
+            }
+        }
+
+        if prochain_entretien_utilisation < maintenant {
+            match entretien_utilisation_journaliere(middleware).await {
+                Ok(()) => {
+                    prochain_entretien_utilisation = maintenant + intervalle_entretien_utilisation;
+                    debug!("domaines_core.entretien Prochain entretien utilisation: {:?}", prochain_entretien_utilisation);
+                },
+                Err(e) => warn!("domaines_core.entretien Erreur entretien utilisation : {:?}", e)
+            }
+            // Debit de la consommation journaliere des clients prepayes, apres le releve d'utilisation
+            if let Err(e) = entretien_consommation_prepayee(gestionnaire, middleware).await {
+                warn!("domaines_core.entretien Erreur entretien consommation prepayee : {:?}", e)
+            }
+        }
+
         // Sleep
         tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
     }
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+
+use log::{debug, error, info};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::certificats::ValidateurX509;
+use millegrilles_common_rust::chrono;
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::constantes::{Securite, CHAMP_CREATION, CHAMP_MODIFICATION};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
+use millegrilles_common_rust::middleware::sauvegarder_traiter_transaction_serializable_v2;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{epochseconds, optionepochseconds};
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, convertir_to_bson, MongoDao};
+use millegrilles_common_rust::mongodb::options::UpdateOptions;
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::config_ressources::{get_periode_grace, get_rappels_expiration, get_seuils_quota};
+use crate::constantes;
+use crate::domaine_hebergement::GestionnaireDomaineHebergement;
+use crate::etat_clients::{charger_quotas_plans, charger_utilisation_clients, fusionner_quota, pourcentage_quota};
+use crate::periodes::{debut_jour, format_mois};
+use crate::releves::calculer_consommation_jour;
+use crate::structure_donnees::{ClientHebergementRow, QuotaClient, UtilisationClient, UtilisationJourRow};
+use crate::transactions::TransactionEcritureGrandLivre;
+
+/// Enregistre une notification pour un client. Retourne true si la notification est nouvelle
+/// (i.e. l'evenement correspondant doit etre emis), false si elle a deja ete enregistree.
+pub async fn enregistrer_notification<M>(middleware: &M, idmg: &str, type_notification: &str, cle: &str)
+    -> Result<bool, Error>
+    where M: MongoDao
+{
+    let filtre = doc! {"idmg": idmg, "type": type_notification, "cle": cle};
+    let ops = doc! {"$setOnInsert": {CHAMP_CREATION: Utc::now()}};
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection = middleware.get_collection(constantes::COLLECTION_NOTIFICATIONS_NOM)?;
+    let resultat = collection.update_one(filtre, ops, options).await?;
+    Ok(resultat.upserted_id.is_some())
+}
+
+#[derive(Serialize)]
+struct EvenementExpirationClient {
+    idmg: String,
+    #[serde(with = "epochseconds")]
+    expiration: DateTime<Utc>,
+    /// Delai du rappel (jours) pour clientExpirationProche.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jours: Option<i64>,
+    #[serde(default, with = "optionepochseconds", skip_serializing_if = "Option::is_none")]
+    fin_grace: Option<DateTime<Utc>>,
+}
+
+/// Emet un evenement d'expiration ou de quota pour une MilleGrille hebergee, partitionne par idmg.
+/// Le contenu (utilisation, limites, dates) est reserve au niveau 3.protege.
+async fn emettre_evenement_client<M, S>(middleware: &M, action: &str, idmg: &str, evenement: &S)
+    -> Result<(), Error>
+    where M: GenerateurMessages, S: Serialize + Send + Sync
+{
+    let routage = RoutageMessageAction::builder(constantes::DOMAINE_NOM, action, vec![Securite::L3Protege])
+        .partition(idmg.to_string())
+        .build();
+    middleware.emettre_evenement(routage, evenement).await?;
+    Ok(())
+}
+
+async fn emettre_evenement_expiration<M>(middleware: &M, action: &str, evenement: EvenementExpirationClient)
+    -> Result<(), Error>
+    where M: GenerateurMessages
+{
+    emettre_evenement_client(middleware, action, evenement.idmg.as_str(), &evenement).await
+}
+
+/// Convertit les expirations de clients conservees en epoch secondes (format d'origine de
+/// sauvegarderClient) en dates BSON. Les filtres de date ($lt, $gt) ignorent les valeurs entieres.
+pub async fn migrer_expiration_clients<M>(middleware: &M) -> Result<(), Error>
+    where M: MongoDao
+{
+    let filtre = doc! {"expiration": {"$type": ["int", "long"]}};
+    let ops = vec![
+        doc! {"$set": {"expiration": {"$toDate": {"$multiply": [{"$toLong": "$expiration"}, 1000]}}}},
+    ];
+    for nom_collection in [constantes::COLLECTION_CLIENTS_NOM, constantes::COLLECTION_CLIENTS_ARCHIVES_NOM] {
+        let collection = middleware.get_collection(nom_collection)?;
+        let resultat = collection.update_many(filtre.clone(), ops.clone(), None).await?;
+        if resultat.modified_count > 0 {
+            info!("migrer_expiration_clients {} expirations converties en date dans {}", resultat.modified_count, nom_collection);
+        }
+    }
+    Ok(())
+}
+
+/// Emet les rappels d'expiration prochaine ainsi que les evenements d'expiration et de fin
+/// de periode de grace. Chaque evenement est emis une seule fois par date d'expiration.
+pub async fn entretien_expirations<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("entretien_expirations Debut");
+    let maintenant = Utc::now();
+    let rappels = get_rappels_expiration();
+    let periode_grace = get_periode_grace();
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+
+    // Rappels avant expiration
+    if let Some(delai_max) = rappels.last() {
+        let limite = maintenant + chrono::Duration::days(*delai_max);
+        let filtre = doc! {"actif": {"$ne": false}, "expiration": {"$gt": maintenant, "$lte": limite}};
+        let mut curseur = collection.find(filtre, None).await?;
+        while curseur.advance().await? {
+            let client = match curseur.deserialize_current() {
+                Ok(inner) => inner,
+                Err(e) => {
+                    error!("entretien_expirations Erreur mapping client, skip : {:?}", e);
+                    continue
+                }
+            };
+            let expiration = match client.expiration {
+                Some(inner) => inner,
+                None => continue
+            };
+
+            // Utiliser le plus petit delai qui couvre le temps restant (evite d'emettre les rappels anterieurs)
+            let jours_restants = (expiration - maintenant).num_days();
+            let delai = match rappels.iter().find(|d| **d >= jours_restants) {
+                Some(inner) => *inner,
+                None => continue
+            };
+
+            let cle = format!("{}:{}", delai, expiration.timestamp());
+            if enregistrer_notification(middleware, client.idmg.as_str(), constantes::EVENEMENT_CLIENT_EXPIRATION_PROCHE, cle.as_str()).await? {
+                info!("entretien_expirations Client {} expire dans {} jours", client.idmg, jours_restants);
+                let evenement = EvenementExpirationClient { idmg: client.idmg, expiration, jours: Some(delai), fin_grace: None };
+                emettre_evenement_expiration(middleware, constantes::EVENEMENT_CLIENT_EXPIRATION_PROCHE, evenement).await?;
+            }
+        }
+    }
+
+    // Clients expires et fin de periode de grace. Les clients expires depuis plus longtemps que la
+    // periode de grace (et sa marge) ont deja recu leurs evenements, ils ne sont plus parcourus.
+    let limite_grace = maintenant - periode_grace - chrono::Duration::days(constantes::CONST_MARGE_FIN_GRACE_JOURS);
+    let filtre = doc! {"actif": {"$ne": false}, "expiration": {"$lte": maintenant, "$gt": limite_grace}};
+    let mut curseur = collection.find(filtre, None).await?;
+    while curseur.advance().await? {
+        let client = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("entretien_expirations Erreur mapping client, skip : {:?}", e);
+                continue
+            }
+        };
+        let expiration = match client.expiration {
+            Some(inner) => inner,
+            None => continue
+        };
+        let fin_grace = expiration + periode_grace;
+        let cle = expiration.timestamp().to_string();
+
+        if enregistrer_notification(middleware, client.idmg.as_str(), constantes::EVENEMENT_CLIENT_EXPIRE, cle.as_str()).await? {
+            info!("entretien_expirations Client {} expire, fin de grace {:?}", client.idmg, fin_grace);
+            let evenement = EvenementExpirationClient { idmg: client.idmg.clone(), expiration, jours: None, fin_grace: Some(fin_grace) };
+            emettre_evenement_expiration(middleware, constantes::EVENEMENT_CLIENT_EXPIRE, evenement).await?;
+        }
+
+        if fin_grace <= maintenant {
+            if enregistrer_notification(middleware, client.idmg.as_str(), constantes::EVENEMENT_CLIENT_FIN_GRACE, cle.as_str()).await? {
+                info!("entretien_expirations Client {} fin de la periode de grace", client.idmg);
+                let evenement = EvenementExpirationClient { idmg: client.idmg, expiration, jours: None, fin_grace: Some(fin_grace) };
+                emettre_evenement_expiration(middleware, constantes::EVENEMENT_CLIENT_FIN_GRACE, evenement).await?;
+            }
+        }
+    }
+
+    debug!("entretien_expirations Fin");
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct EvenementQuotaClient<'a> {
+    idmg: &'a str,
+    /// Seuil atteint en pourcentage (100 pour quotaDepasse).
+    seuil: i64,
+    pourcentage: f64,
+    quota: &'a QuotaClient,
+    utilisation: &'a UtilisationClient,
+}
+
+/// Emet quotaAvertissement lorsqu'un seuil est franchi et quotaDepasse lorsque le quota est atteint.
+/// Chaque seuil est emis une seule fois, il est rearme lorsque l'utilisation redescend sous le seuil.
+pub async fn verifier_seuils_quota<M>(middleware: &M, idmg: &str, quota: &QuotaClient, utilisation: &UtilisationClient)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    let pourcentage = match pourcentage_quota(quota, utilisation) {
+        Some(inner) => inner,
+        None => return Ok(())  // Aucune limite
+    };
+    let seuils = get_seuils_quota();
+
+    // Rearmer les seuils qui ne sont plus atteints
+    let mut cles_rearmer: Vec<String> = seuils.iter()
+        .filter(|s| (**s as f64) > pourcentage)
+        .map(|s| s.to_string())
+        .collect();
+    if pourcentage < 100.0 {
+        cles_rearmer.push(String::from("100"));
+    }
+    if ! cles_rearmer.is_empty() {
+        let filtre = doc! {
+            "idmg": idmg,
+            "type": {"$in": [constantes::EVENEMENT_QUOTA_AVERTISSEMENT, constantes::EVENEMENT_QUOTA_DEPASSE]},
+            "cle": {"$in": cles_rearmer},
+        };
+        let collection = middleware.get_collection(constantes::COLLECTION_NOTIFICATIONS_NOM)?;
+        collection.delete_many(filtre, None).await?;
+    }
+
+    let (action, seuil) = if pourcentage >= 100.0 {
+        (constantes::EVENEMENT_QUOTA_DEPASSE, 100)
+    } else {
+        match seuils.iter().rev().find(|s| pourcentage >= **s as f64) {
+            Some(inner) => (constantes::EVENEMENT_QUOTA_AVERTISSEMENT, *inner),
+            None => return Ok(())
+        }
+    };
+
+    if enregistrer_notification(middleware, idmg, action, seuil.to_string().as_str()).await? {
+        info!("verifier_seuils_quota Client {} a atteint {}% du quota ({})", idmg, seuil, action);
+        let evenement = EvenementQuotaClient { idmg, seuil, pourcentage, quota, utilisation };
+        emettre_evenement_client(middleware, action, idmg, &evenement).await?;
+    }
+
+    Ok(())
+}
+
+/// Verifie les seuils de quota de tous les clients actifs.
+pub async fn entretien_quotas<M>(middleware: &M) -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("entretien_quotas Debut");
+    let quotas_plans = charger_quotas_plans(middleware).await?;
+    let mut utilisation_clients = charger_utilisation_clients(middleware, doc! {}).await?;
+
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let mut curseur = collection.find(doc! {"actif": {"$ne": false}}, None).await?;
+    while curseur.advance().await? {
+        let client = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("entretien_quotas Erreur mapping client, skip : {:?}", e);
+                continue
+            }
+        };
+        let quota_plan = match client.plan_id.as_ref() {
+            Some(plan_id) => quotas_plans.get(plan_id).cloned().flatten(),
+            None => None
+        };
+        let quota = match fusionner_quota(quota_plan, &client) {
+            Some(inner) => inner,
+            None => continue
+        };
+        let utilisation = utilisation_clients.remove(&client.idmg).unwrap_or_default();
+        if let Err(e) = verifier_seuils_quota(middleware, client.idmg.as_str(), &quota, &utilisation).await {
+            error!("entretien_quotas Erreur verification seuils client {} : {:?}", client.idmg, e);
+        }
+    }
+
+    debug!("entretien_quotas Fin");
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct TransfertJourClient {
+    #[serde(rename="_id")]
+    idmg: String,
+    octets_upload: i64,
+    octets_download: i64,
+}
+
+/// Charge les transferts de la journee par client (toutes instances de consignation).
+async fn charger_transferts_jour<M>(middleware: &M, jour: &DateTime<Utc>) -> Result<HashMap<String, TransfertJourClient>, Error>
+    where M: MongoDao
+{
+    let pipeline = vec![
+        doc! {"$match": {"jour": *jour}},
+        doc! {"$group": {
+            "_id": "$idmg",
+            "octets_upload": {"$sum": "$octets_upload"},
+            "octets_download": {"$sum": "$octets_download"},
+        }},
+    ];
+    let collection = middleware.get_collection(constantes::COLLECTION_TRANSFERTS_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut transferts = HashMap::new();
+    while let Some(resultat) = curseur.next().await {
+        let transfert: TransfertJourClient = convertir_bson_deserializable(resultat?)?;
+        transferts.insert(transfert.idmg.clone(), transfert);
+    }
+    Ok(transferts)
+}
+
+/// Conserve l'utilisation de chaque client pour la journee courante. Le releve de la journee est
+/// remplace a chaque passe (un seul releve par client par jour).
+pub async fn entretien_utilisation_journaliere<M>(middleware: &M) -> Result<(), Error>
+    where M: MongoDao
+{
+    debug!("entretien_utilisation_journaliere Debut");
+    let jour = debut_jour(&Utc::now());
+    let mut utilisation_clients = charger_utilisation_clients(middleware, doc! {}).await?;
+    let mut transferts = charger_transferts_jour(middleware, &jour).await?;
+
+    let options = UpdateOptions::builder().upsert(true).build();
+    let collection_releves = middleware.get_collection(constantes::COLLECTION_UTILISATION_JOURNALIERE_NOM)?;
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let mut curseur = collection.find(doc! {"actif": {"$ne": false}}, None).await?;
+    while curseur.advance().await? {
+        let client = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("entretien_utilisation_journaliere Erreur mapping client, skip : {:?}", e);
+                continue
+            }
+        };
+        let utilisation = utilisation_clients.remove(&client.idmg).unwrap_or_default();
+        let transfert = transferts.remove(&client.idmg);
+        let row = UtilisationJourRow {
+            idmg: client.idmg,
+            jour,
+            nombre_fichiers: utilisation.nombre_fichiers,
+            taille_totale: utilisation.taille_totale,
+            octets_upload: transfert.as_ref().map(|t| t.octets_upload),
+            octets_download: transfert.as_ref().map(|t| t.octets_download),
+        };
+        let filtre = doc! {"idmg": &row.idmg, "jour": jour};
+        let ops = doc! {
+            "$set": convertir_to_bson(&row)?,
+            "$setOnInsert": {CHAMP_CREATION: Utc::now()},
+            "$currentDate": {CHAMP_MODIFICATION: true},
+        };
+        collection_releves.update_one(filtre, ops, options.clone()).await?;
+    }
+
+    debug!("entretien_utilisation_journaliere Fin");
+    Ok(())
+}
+
+/// Debite au grand livre la consommation de la veille des clients prepayes. Une seule ecriture par
+/// client par jour. Aucune ecriture si le releve du mois est deja finalise, l'ajustement de la
+/// finalisation couvre alors la journee (de meme que les journees manquees).
+pub async fn entretien_consommation_prepayee<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M)
+    -> Result<(), Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("entretien_consommation_prepayee Debut");
+    let veille = debut_jour(&Utc::now()) - chrono::Duration::days(1);
+    let jour = veille.format("%Y-%m-%d").to_string();
+    let mois = format_mois(&veille);
+
+    let collection_grand_livre = middleware.get_collection(constantes::COLLECTION_GRAND_LIVRE_NOM)?;
+    let collection_releves = middleware.get_collection(constantes::COLLECTION_RELEVES_NOM)?;
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let mut curseur = collection.find(doc! {"prepaye": true, "actif": {"$ne": false}}, None).await?;
+    while curseur.advance().await? {
+        let client = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("entretien_consommation_prepayee Erreur mapping client, skip : {:?}", e);
+                continue
+            }
+        };
+
+        let filtre_debit = doc! {"idmg": &client.idmg, "jour": &jour};
+        if collection_grand_livre.find_one(filtre_debit, None).await?.is_some() {
+            continue
+        }
+        let filtre_finalise = doc! {"idmg": &client.idmg, "mois": &mois, constantes::CHAMP_ETAT: constantes::ETAT_RELEVE_FINALISE};
+        if collection_releves.find_one(filtre_finalise, None).await?.is_some() {
+            continue
+        }
+
+        let montant = calculer_consommation_jour(middleware, &client, &veille).await?;
+        if montant <= 0 {
+            continue
+        }
+
+        let transaction = TransactionEcritureGrandLivre {
+            idmg: client.idmg,
+            montant,
+            reference: Some(constantes::REFERENCE_ECRITURE_CONSOMMATION.to_string()),
+            mois: Some(mois.clone()),
+            jour: Some(jour.clone()),
+        };
+        sauvegarder_traiter_transaction_serializable_v2(
+            middleware, &transaction, gestionnaire, constantes::DOMAINE_NOM, constantes::TRANSACTION_DEBITER_CLIENT).await?;
+    }
+
+    debug!("entretien_consommation_prepayee Fin");
+    Ok(())
+}
@@ -0,0 +1,558 @@
+use std::collections::HashMap;
+
+use log::{debug, error, warn};
+use millegrilles_common_rust::bson::{doc, Document};
+use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use serde::Deserialize;
+
+use crate::config_ressources::get_periode_grace;
+use crate::constantes;
+use crate::structure_donnees::{ClientHebergementRow, EcritureGrandLivreRow, PlanHebergementRow, QuotaClient, UtilisationClient};
+
+/// Raison pour laquelle un client est limite a la lecture seule.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RaisonDegradation {
+    /// L'expiration est depassee, le client est dans sa periode de grace.
+    Expire,
+    /// L'espace ou le nombre de fichiers depasse le quota.
+    Quota,
+    /// L'hebergement est suspendu par un operateur.
+    Suspendu,
+    /// Le solde prepaye est epuise.
+    Solde,
+}
+
+impl RaisonDegradation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Expire => "expire",
+            Self::Quota => "quota",
+            Self::Suspendu => "suspendu",
+            Self::Solde => "solde",
+        }
+    }
+}
+
+/// Raison pour laquelle un client n'a plus acces a l'hebergement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RaisonRefus {
+    Inactif,
+    /// L'expiration et la periode de grace sont depassees.
+    Expire,
+}
+
+impl RaisonRefus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inactif => "inactif",
+            Self::Expire => "expire",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EtatAccesClient {
+    /// Acces en lecture et ecriture.
+    Normal,
+    LectureSeule(RaisonDegradation),
+    Refuse(RaisonRefus),
+}
+
+impl EtatAccesClient {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::LectureSeule(_) => "lectureSeule",
+            Self::Refuse(_) => "refuse",
+        }
+    }
+
+    pub fn get_raison(&self) -> Option<&'static str> {
+        match self {
+            Self::Normal => None,
+            Self::LectureSeule(inner) => Some(inner.as_str()),
+            Self::Refuse(inner) => Some(inner.as_str()),
+        }
+    }
+}
+
+/// Rangee d'agregation de l'utilisation, _id est le idmg du client.
+#[derive(Deserialize)]
+struct UtilisationClientRow {
+    #[serde(rename="_id")]
+    idmg: String,
+    #[serde(flatten)]
+    utilisation: UtilisationClient,
+}
+
+/// Calcule l'utilisation courante (fichiers non retires) des clients qui correspondent au filtre.
+pub async fn charger_utilisation_clients<M>(middleware: &M, filtre: Document) -> Result<HashMap<String, UtilisationClient>, Error>
+    where M: MongoDao
+{
+    let mut filtre = filtre;
+    filtre.insert(constantes::CHAMP_RETIRE, doc! {"$ne": true});
+
+    // Un fichier est synchronise lorsque date_sync est presente et qu'aucune sync n'est en cours.
+    let condition_sync = doc! {"$and": [
+        {"$ifNull": [format!("${}", constantes::CHAMP_DATE_SYNC), false]},
+        {"$ne": [format!("${}", constantes::CHAMP_SYNC_EN_COURS), true]},
+    ]};
+    let pipeline = vec![
+        doc! {"$match": filtre},
+        doc! {"$group": {
+            "_id": "$idmg",
+            "nombre_fichiers": {"$sum": 1},
+            "taille_totale": {"$sum": "$taille_chiffre"},
+            "nombre_fichiers_synchronises": {"$sum": {"$cond": [condition_sync.clone(), 1, 0]}},
+            "nombre_fichiers_attente": {"$sum": {"$cond": [condition_sync, 0, 1]}},
+        }},
+    ];
+    let collection = middleware.get_collection(constantes::COLLECTION_FICHIERS_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut utilisation_clients = HashMap::new();
+    while let Some(resultat) = curseur.next().await {
+        let row: UtilisationClientRow = convertir_bson_deserializable(resultat?)?;
+        utilisation_clients.insert(row.idmg, row.utilisation);
+    }
+    Ok(utilisation_clients)
+}
+
+/// Calcule l'utilisation courante (fichiers non retires) d'un client.
+pub async fn charger_utilisation_client<M,S>(middleware: &M, idmg: S) -> Result<UtilisationClient, Error>
+    where M: MongoDao, S: AsRef<str>
+{
+    let idmg = idmg.as_ref();
+    let mut utilisation_clients = charger_utilisation_clients(middleware, doc! {"idmg": idmg}).await?;
+    Ok(utilisation_clients.remove(idmg).unwrap_or_default())
+}
+
+pub async fn charger_plan<M,S>(middleware: &M, plan_id: S) -> Result<Option<PlanHebergementRow>, Error>
+    where M: MongoDao, S: AsRef<str>
+{
+    let filtre = doc! {"plan_id": plan_id.as_ref()};
+    let collection = middleware.get_collection_typed::<PlanHebergementRow>(constantes::COLLECTION_PLANS_NOM)?;
+    Ok(collection.find_one(filtre, None).await?)
+}
+
+/// Charge le quota de tous les plans, par plan_id. Evite de charger le plan pour chaque client.
+pub async fn charger_quotas_plans<M>(middleware: &M) -> Result<HashMap<String, Option<QuotaClient>>, Error>
+    where M: MongoDao
+{
+    let mut quotas_plans = HashMap::new();
+    let collection = middleware.get_collection_typed::<PlanHebergementRow>(constantes::COLLECTION_PLANS_NOM)?;
+    let mut curseur = collection.find(doc!{}, None).await?;
+    while curseur.advance().await? {
+        match curseur.deserialize_current() {
+            Ok(inner) => { quotas_plans.insert(inner.plan_id, inner.quota); },
+            Err(e) => error!("charger_quotas_plans Erreur mapping plan, skip : {:?}", e)
+        }
+    }
+    Ok(quotas_plans)
+}
+
+/// Quota effectif d'un client : limites du plan, remplacees par les limites propres au client.
+pub async fn charger_quota_client<M>(middleware: &M, client: &ClientHebergementRow) -> Result<Option<QuotaClient>, Error>
+    where M: MongoDao
+{
+    let quota_plan = match client.plan_id.as_ref() {
+        Some(plan_id) => match charger_plan(middleware, plan_id).await? {
+            Some(plan) => plan.quota,
+            None => {
+                warn!("charger_quota_client Plan {} inconnu pour client {}", plan_id, client.idmg);
+                None
+            }
+        },
+        None => None
+    };
+
+    Ok(fusionner_quota(quota_plan, client))
+}
+
+/// Combine le quota du plan avec les limites propres au client.
+pub fn fusionner_quota(quota_plan: Option<QuotaClient>, client: &ClientHebergementRow) -> Option<QuotaClient> {
+    match (quota_plan, client.quota.as_ref()) {
+        (Some(plan), Some(remplacement)) => Some(plan.fusionner(remplacement)),
+        (Some(plan), None) => Some(plan),
+        (None, Some(remplacement)) => Some(remplacement.clone()),
+        (None, None) => None
+    }
+}
+
+/// Raison du refus d'un nouveau fichier par le quota. Le code est retourne aux serveurs de consignation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RefusQuota {
+    TailleFichier,
+    Espace,
+    NombreFichiers,
+    /// La taille du fichier est requise pour verifier le quota.
+    TailleManquante,
+}
+
+impl RefusQuota {
+    pub fn code(&self) -> usize {
+        match self {
+            Self::TailleFichier => 50,
+            Self::Espace => 51,
+            Self::NombreFichiers => 52,
+            Self::TailleManquante => 53,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::TailleFichier => "Taille maximale de fichier depassee",
+            Self::Espace => "Espace de l'hebergement insuffisant",
+            Self::NombreFichiers => "Nombre maximal de fichiers atteint",
+            Self::TailleManquante => "Taille du fichier (taille_chiffre) requise",
+        }
+    }
+}
+
+/// Verifie si un nouveau fichier de la taille indiquee peut etre ajoute sans depasser le quota.
+pub fn verifier_quota_fichier(quota: &QuotaClient, utilisation: &UtilisationClient, taille: Option<i64>)
+    -> Option<RefusQuota>
+{
+    if quota.taille_max.is_some() || quota.taille_fichier_max.is_some() {
+        if taille.is_none() { return Some(RefusQuota::TailleManquante) }
+    }
+    let taille = taille.unwrap_or(0);
+
+    if let Some(taille_fichier_max) = quota.taille_fichier_max {
+        if taille > taille_fichier_max { return Some(RefusQuota::TailleFichier) }
+    }
+    if let Some(taille_max) = quota.taille_max {
+        if utilisation.taille_totale + taille > taille_max { return Some(RefusQuota::Espace) }
+    }
+    if let Some(nombre_fichiers_max) = quota.nombre_fichiers_max {
+        if utilisation.nombre_fichiers + 1 > nombre_fichiers_max { return Some(RefusQuota::NombreFichiers) }
+    }
+    None
+}
+
+/// Pourcentage utilise du quota : le plus eleve entre l'espace et le nombre de fichiers.
+pub fn pourcentage_quota(quota: &QuotaClient, utilisation: &UtilisationClient) -> Option<f64> {
+    let pct_taille = quota.taille_max.filter(|m| *m > 0)
+        .map(|m| utilisation.taille_totale as f64 * 100.0 / m as f64);
+    let pct_fichiers = quota.nombre_fichiers_max.filter(|m| *m > 0)
+        .map(|m| utilisation.nombre_fichiers as f64 * 100.0 / m as f64);
+    match (pct_taille, pct_fichiers) {
+        (Some(t), Some(f)) => Some(t.max(f)),
+        (Some(t), None) => Some(t),
+        (None, Some(f)) => Some(f),
+        (None, None) => None
+    }
+}
+
+/// Retourne true si l'utilisation depasse un des maximums du quota.
+pub fn quota_depasse(quota: &QuotaClient, utilisation: &UtilisationClient) -> bool {
+    if let Some(taille_max) = quota.taille_max {
+        if utilisation.taille_totale >= taille_max { return true }
+    }
+    if let Some(nombre_fichiers_max) = quota.nombre_fichiers_max {
+        if utilisation.nombre_fichiers >= nombre_fichiers_max { return true }
+    }
+    false
+}
+
+/// Calcule le solde (cents) du grand livre d'un client : credits moins debits.
+pub async fn charger_solde_client<M,S>(middleware: &M, idmg: S) -> Result<i64, Error>
+    where M: MongoDao, S: AsRef<str>
+{
+    let pipeline = vec![
+        doc! {"$match": {"idmg": idmg.as_ref()}},
+        doc! {"$group": {
+            "_id": "$idmg",
+            "solde": {"$sum": {"$cond": [
+                {"$eq": ["$type", constantes::TYPE_ECRITURE_CREDIT]}, "$montant", {"$multiply": ["$montant", -1]}
+            ]}},
+        }},
+    ];
+    let collection = middleware.get_collection(constantes::COLLECTION_GRAND_LIVRE_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    match curseur.next().await {
+        Some(resultat) => Ok(resultat?.get_i64("solde").unwrap_or(0)),
+        None => Ok(0)
+    }
+}
+
+/// Montant net (cents) debite au grand livre d'un client pour un mois : debits moins credits
+/// des ecritures associees au mois.
+pub async fn charger_debits_mois<M>(middleware: &M, idmg: &str, mois: &str) -> Result<i64, Error>
+    where M: MongoDao
+{
+    let pipeline = vec![
+        doc! {"$match": {"idmg": idmg, "mois": mois}},
+        doc! {"$group": {
+            "_id": "$idmg",
+            "debits": {"$sum": {"$cond": [
+                {"$eq": ["$type", constantes::TYPE_ECRITURE_DEBIT]}, "$montant", {"$multiply": ["$montant", -1]}
+            ]}},
+        }},
+    ];
+    let collection = middleware.get_collection(constantes::COLLECTION_GRAND_LIVRE_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    match curseur.next().await {
+        Some(resultat) => Ok(resultat?.get_i64("debits").unwrap_or(0)),
+        None => Ok(0)
+    }
+}
+
+/// Ecritures du grand livre d'un client associees a un mois.
+pub async fn charger_ecritures_mois<M>(middleware: &M, idmg: &str, mois: &str) -> Result<Vec<EcritureGrandLivreRow>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! {"idmg": idmg, "mois": mois};
+    let collection = middleware.get_collection_typed::<EcritureGrandLivreRow>(constantes::COLLECTION_GRAND_LIVRE_NOM)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut ecritures = Vec::new();
+    while curseur.advance().await? {
+        match curseur.deserialize_current() {
+            Ok(inner) => ecritures.push(inner),
+            Err(e) => error!("charger_ecritures_mois Erreur mapping ecriture, skip : {:?}", e)
+        }
+    }
+    Ok(ecritures)
+}
+
+/// Retourne true si le releve du mois est deja regle au grand livre : debit manuel ou ajustement
+/// de finalisation. Les debits de consommation journaliere (jour present) ne reglent pas le releve.
+pub fn releve_deja_debite(ecritures: &[EcritureGrandLivreRow], mois: &str) -> bool {
+    ecritures.iter().any(|e| {
+        e.mois.as_deref() == Some(mois) && e.jour.is_none() && (
+            e.type_ecriture.as_str() == constantes::TYPE_ECRITURE_DEBIT ||
+            e.reference.as_deref() == Some(constantes::REFERENCE_ECRITURE_RELEVE)
+        )
+    })
+}
+
+/// Solde du grand livre si le client est prepaye, None sinon.
+pub async fn charger_solde_prepaye<M>(middleware: &M, client: &ClientHebergementRow) -> Result<Option<i64>, Error>
+    where M: MongoDao
+{
+    match client.prepaye {
+        Some(true) => Ok(Some(charger_solde_client(middleware, client.idmg.as_str()).await?)),
+        _ => Ok(None)
+    }
+}
+
+/// Determine l'etat d'acces d'un client a partir de son expiration, de la periode de grace,
+/// de son utilisation, de la suspension et du solde prepaye (None si le client n'est pas prepaye).
+pub fn determiner_etat_acces(client: &ClientHebergementRow, quota: Option<&QuotaClient>,
+                             utilisation: &UtilisationClient, solde: Option<i64>, maintenant: &DateTime<Utc>)
+    -> EtatAccesClient
+{
+    if client.actif == Some(false) {
+        return EtatAccesClient::Refuse(RaisonRefus::Inactif)
+    }
+
+    if let Some(expiration) = client.expiration.as_ref() {
+        if expiration < maintenant {
+            if *expiration + get_periode_grace() < *maintenant {
+                return EtatAccesClient::Refuse(RaisonRefus::Expire)
+            }
+            return EtatAccesClient::LectureSeule(RaisonDegradation::Expire)
+        }
+    }
+
+    if client.suspendu == Some(true) {
+        return EtatAccesClient::LectureSeule(RaisonDegradation::Suspendu)
+    }
+
+    if let Some(solde) = solde {
+        if solde <= 0 {
+            return EtatAccesClient::LectureSeule(RaisonDegradation::Solde)
+        }
+    }
+
+    if let Some(quota) = quota {
+        if quota_depasse(quota, utilisation) {
+            return EtatAccesClient::LectureSeule(RaisonDegradation::Quota)
+        }
+    }
+
+    EtatAccesClient::Normal
+}
+
+/// Date limite d'acces du client : expiration plus la periode de grace si l'expiration est depassee.
+pub fn get_fin_acces(client: &ClientHebergementRow, etat: &EtatAccesClient) -> Option<DateTime<Utc>> {
+    match etat {
+        EtatAccesClient::LectureSeule(RaisonDegradation::Expire) => client.expiration.map(|e| e + get_periode_grace()),
+        _ => client.expiration
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use millegrilles_common_rust::chrono::{Duration, TimeZone};
+
+    fn quota(taille_max: Option<i64>, nombre_fichiers_max: Option<i64>, taille_fichier_max: Option<i64>) -> QuotaClient {
+        QuotaClient { taille_max, nombre_fichiers_max, taille_fichier_max }
+    }
+
+    fn utilisation(nombre_fichiers: i64, taille_totale: i64) -> UtilisationClient {
+        UtilisationClient { nombre_fichiers, taille_totale, ..Default::default() }
+    }
+
+    fn maintenant() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 15, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_verifier_quota_fichier_espace() {
+        let quota = quota(Some(1000), None, None);
+        assert_eq!(None, verifier_quota_fichier(&quota, &utilisation(1, 900), Some(100)));
+        assert_eq!(Some(RefusQuota::Espace), verifier_quota_fichier(&quota, &utilisation(1, 900), Some(101)));
+    }
+
+    #[test]
+    fn test_verifier_quota_fichier_taille_fichier() {
+        let quota = quota(None, None, Some(500));
+        assert_eq!(None, verifier_quota_fichier(&quota, &utilisation(0, 0), Some(500)));
+        assert_eq!(Some(RefusQuota::TailleFichier), verifier_quota_fichier(&quota, &utilisation(0, 0), Some(501)));
+    }
+
+    #[test]
+    fn test_verifier_quota_fichier_nombre_fichiers() {
+        let quota = quota(None, Some(10), None);
+        assert_eq!(None, verifier_quota_fichier(&quota, &utilisation(9, 0), None));
+        assert_eq!(Some(RefusQuota::NombreFichiers), verifier_quota_fichier(&quota, &utilisation(10, 0), None));
+    }
+
+    #[test]
+    fn test_verifier_quota_fichier_taille_manquante() {
+        assert_eq!(Some(RefusQuota::TailleManquante), verifier_quota_fichier(&quota(Some(1000), None, None), &utilisation(0, 0), None));
+        assert_eq!(Some(RefusQuota::TailleManquante), verifier_quota_fichier(&quota(None, None, Some(1000)), &utilisation(0, 0), None));
+    }
+
+    #[test]
+    fn test_verifier_quota_fichier_sans_limite() {
+        assert_eq!(None, verifier_quota_fichier(&quota(None, None, None), &utilisation(1_000_000, i64::MAX / 2), None));
+    }
+
+    #[test]
+    fn test_verifier_quota_fichier_quota_zero() {
+        let quota = quota(Some(0), Some(0), None);
+        assert_eq!(Some(RefusQuota::Espace), verifier_quota_fichier(&quota, &utilisation(0, 0), Some(1)));
+        assert_eq!(Some(RefusQuota::NombreFichiers), verifier_quota_fichier(&quota, &utilisation(0, 0), Some(0)));
+    }
+
+    #[test]
+    fn test_refus_quota_codes() {
+        let codes: Vec<usize> = [RefusQuota::TailleFichier, RefusQuota::Espace, RefusQuota::NombreFichiers, RefusQuota::TailleManquante]
+            .iter().map(|r| r.code()).collect();
+        assert_eq!(vec![50, 51, 52, 53], codes);
+    }
+
+    #[test]
+    fn test_pourcentage_quota() {
+        assert_eq!(None, pourcentage_quota(&quota(None, None, None), &utilisation(5, 500)));
+        // Une limite a 0 est ignoree (division par zero)
+        assert_eq!(None, pourcentage_quota(&quota(Some(0), Some(0), None), &utilisation(5, 500)));
+        assert_eq!(Some(50.0), pourcentage_quota(&quota(Some(1000), None, None), &utilisation(5, 500)));
+        // Le plus eleve entre l'espace et le nombre de fichiers
+        assert_eq!(Some(80.0), pourcentage_quota(&quota(Some(1000), Some(10), None), &utilisation(8, 500)));
+    }
+
+    #[test]
+    fn test_quota_depasse() {
+        assert!(!quota_depasse(&quota(None, None, None), &utilisation(1_000, 1_000_000)));
+        assert!(!quota_depasse(&quota(Some(1000), Some(10), None), &utilisation(9, 999)));
+        assert!(quota_depasse(&quota(Some(1000), None, None), &utilisation(0, 1000)));
+        assert!(quota_depasse(&quota(None, Some(10), None), &utilisation(10, 0)));
+        // Quota a 0 : aucun espace permis
+        assert!(quota_depasse(&quota(Some(0), None, None), &utilisation(0, 0)));
+    }
+
+    #[test]
+    fn test_fusionner_quota() {
+        let plan = quota(Some(1000), Some(10), None);
+        let remplacement = quota(None, Some(20), Some(5));
+
+        let quota_client = fusionner_quota(Some(plan.clone()), &ClientHebergementRow { quota: Some(remplacement.clone()), ..ClientHebergementRow::nouveau_test() }).unwrap();
+        assert_eq!(Some(1000), quota_client.taille_max);
+        assert_eq!(Some(20), quota_client.nombre_fichiers_max);
+        assert_eq!(Some(5), quota_client.taille_fichier_max);
+
+        let quota_plan = fusionner_quota(Some(plan), &ClientHebergementRow::nouveau_test()).unwrap();
+        assert_eq!(Some(10), quota_plan.nombre_fichiers_max);
+
+        let quota_remplacement = fusionner_quota(None, &ClientHebergementRow { quota: Some(remplacement), ..ClientHebergementRow::nouveau_test() }).unwrap();
+        assert_eq!(None, quota_remplacement.taille_max);
+
+        assert!(fusionner_quota(None, &ClientHebergementRow::nouveau_test()).is_none());
+    }
+
+    fn ecriture(type_ecriture: &str, reference: Option<&str>, jour: Option<&str>) -> EcritureGrandLivreRow {
+        EcritureGrandLivreRow {
+            transaction_id: "transaction".to_string(),
+            idmg: "zTestIdmg".to_string(),
+            type_ecriture: type_ecriture.to_string(),
+            montant: 100,
+            reference: reference.map(|r| r.to_string()),
+            mois: Some("2026-03".to_string()),
+            jour: jour.map(|j| j.to_string()),
+            date: maintenant(),
+        }
+    }
+
+    #[test]
+    fn test_releve_deja_debite() {
+        // Consommation journaliere seulement : le releve n'est pas regle
+        let mut ecritures = vec![ecriture(constantes::TYPE_ECRITURE_DEBIT, Some(constantes::REFERENCE_ECRITURE_CONSOMMATION), Some("2026-03-01"))];
+        assert!(! releve_deja_debite(&ecritures, "2026-03"));
+
+        // Un premier debit manuel du mois regle le releve, un deuxieme debit est refuse
+        ecritures.push(ecriture(constantes::TYPE_ECRITURE_DEBIT, Some("facture 12"), None));
+        assert!(releve_deja_debite(&ecritures, "2026-03"));
+        assert!(! releve_deja_debite(&ecritures, "2026-04"));
+    }
+
+    #[test]
+    fn test_releve_deja_debite_ajustement_finalisation() {
+        // Ajustement de finalisation en credit (consommation journaliere superieure au releve)
+        let ecritures = vec![ecriture(constantes::TYPE_ECRITURE_CREDIT, Some(constantes::REFERENCE_ECRITURE_RELEVE), None)];
+        assert!(releve_deja_debite(&ecritures, "2026-03"));
+
+        // Un credit manuel ne regle pas le releve
+        let ecritures = vec![ecriture(constantes::TYPE_ECRITURE_CREDIT, Some("paiement"), None)];
+        assert!(! releve_deja_debite(&ecritures, "2026-03"));
+    }
+
+    #[test]
+    fn test_determiner_etat_acces_expiration() {
+        let maintenant = maintenant();
+        let grace = get_periode_grace();
+        let utilisation = utilisation(0, 0);
+        let etat = |expiration: DateTime<Utc>| determiner_etat_acces(&ClientHebergementRow { expiration: Some(expiration), ..ClientHebergementRow::nouveau_test() }, None, &utilisation, None, &maintenant);
+
+        assert_eq!(EtatAccesClient::Normal, etat(maintenant));
+        assert_eq!(EtatAccesClient::LectureSeule(RaisonDegradation::Expire), etat(maintenant - Duration::seconds(1)));
+        // Fin de la periode de grace : encore en lecture seule, puis refuse
+        assert_eq!(EtatAccesClient::LectureSeule(RaisonDegradation::Expire), etat(maintenant - grace));
+        assert_eq!(EtatAccesClient::Refuse(RaisonRefus::Expire), etat(maintenant - grace - Duration::seconds(1)));
+    }
+
+    #[test]
+    fn test_determiner_etat_acces_degradation() {
+        let maintenant = maintenant();
+        let utilisation = utilisation(10, 0);
+        let quota = quota(None, Some(10), None);
+
+        let mut inactif = ClientHebergementRow::nouveau_test();
+        inactif.actif = Some(false);
+        assert_eq!(EtatAccesClient::Refuse(RaisonRefus::Inactif), determiner_etat_acces(&inactif, None, &utilisation, None, &maintenant));
+
+        let mut suspendu = ClientHebergementRow::nouveau_test();
+        suspendu.suspendu = Some(true);
+        assert_eq!(EtatAccesClient::LectureSeule(RaisonDegradation::Suspendu), determiner_etat_acces(&suspendu, None, &utilisation, None, &maintenant));
+
+        let normal = ClientHebergementRow::nouveau_test();
+        assert_eq!(EtatAccesClient::LectureSeule(RaisonDegradation::Solde), determiner_etat_acces(&normal, None, &utilisation, Some(0), &maintenant));
+        assert_eq!(EtatAccesClient::LectureSeule(RaisonDegradation::Quota), determiner_etat_acces(&normal, Some(&quota), &utilisation, Some(1), &maintenant));
+        assert_eq!(EtatAccesClient::Normal, determiner_etat_acces(&normal, None, &utilisation, Some(1), &maintenant));
+    }
+}
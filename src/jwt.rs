@@ -5,13 +5,16 @@ use serde::{Deserialize, Serialize};
 use millegrilles_common_rust::jwt_simple::prelude::*;
 
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::{DateTime, Utc};
 use millegrilles_common_rust::common_messages::{InformationDechiffrage, InformationDechiffrageV2};
 use millegrilles_common_rust::constantes::{DOMAINE_NOM_GROSFICHIERS, DOMAINE_NOM_MESSAGERIE, RolesCertificats, Securite};
 use millegrilles_common_rust::formatteur_messages::FormatteurMessage;
 use millegrilles_common_rust::generateur_messages::GenerateurMessages;
 use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::optionepochseconds;
 
 use crate::constantes;
+use crate::structure_donnees::{QuotaClient, UtilisationClient};
 
 pub const CONST_DUREE_TOKEN_VALIDE: u64 = 60 * 60 * 1;
 
@@ -27,6 +30,35 @@ struct ClaimsTokenHebergement {
 
     /// True si le JWT supporte read et write. Si false, read-only.
     readwrite: bool,
+
+    #[serde(flatten)]
+    limites: LimitesTokenHebergement,
+}
+
+/// Limites du client ajoutees aux claims. Les champs sont optionnels et omis s'ils sont absents
+/// pour rester compatible avec les verificateurs existants.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LimitesTokenHebergement {
+    /// Espace restant (bytes) avant d'atteindre le quota.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub espace_disponible: Option<i64>,
+
+    /// Taille maximale (bytes) d'un fichier chiffre.
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub taille_fichier_max: Option<i64>,
+
+    /// Date d'expiration de l'hebergement du client.
+    #[serde(default, with="optionepochseconds", skip_serializing_if="Option::is_none")]
+    pub expiration_client: Option<DateTime<Utc>>,
+}
+
+impl LimitesTokenHebergement {
+    pub fn new(quota: Option<&QuotaClient>, utilisation: &UtilisationClient, expiration_client: Option<DateTime<Utc>>) -> Self {
+        let espace_disponible = quota.and_then(|q| q.taille_max)
+            .map(|taille_max| (taille_max - utilisation.taille_totale).max(0));
+        let taille_fichier_max = quota.and_then(|q| q.taille_fichier_max);
+        Self { espace_disponible, taille_fichier_max, expiration_client }
+    }
 }
 
 // pub async fn verify_jwt<M,S>(middleware: &M, jwt_token: S) -> Result<FichierClaims, Error>
@@ -81,9 +113,11 @@ struct ClaimsTokenHebergement {
 //     })
 // }
 
+/// Genere un JWT d'hebergement. La duree du token ne depasse jamais l'expiration du client.
 pub fn generer_jwt_hebergement<M,U>(
     middleware: &M, idmg: U, readwrite: bool, roles_heberges: Option<Vec<String>>,
-    domaines_heberges: Option<Vec<String>>
+    domaines_heberges: Option<Vec<String>>, expiration_client: Option<DateTime<Utc>>,
+    limites: LimitesTokenHebergement
 )
     -> Result<String, Error>
     where
@@ -95,11 +129,23 @@ pub fn generer_jwt_hebergement<M,U>(
     let info_hebergement = ClaimsTokenHebergement {
         roles: roles_heberges,
         domaines: domaines_heberges,
-        readwrite
+        readwrite,
+        limites,
+    };
+
+    let duree_token = match expiration_client {
+        Some(inner) => {
+            let secondes_restantes = (inner - Utc::now()).num_seconds();
+            if secondes_restantes <= 0 {
+                Err(Error::Str("generer_jwt_hebergement Hebergement expire"))?
+            }
+            CONST_DUREE_TOKEN_VALIDE.min(secondes_restantes as u64)
+        },
+        None => CONST_DUREE_TOKEN_VALIDE
     };
 
     let mut claims = Claims::with_custom_claims(
-        info_hebergement, Duration::from_secs(CONST_DUREE_TOKEN_VALIDE));
+        info_hebergement, Duration::from_secs(duree_token));
     claims.subject = Some(idmg);
 
     // Recuperer cle pour signer le token
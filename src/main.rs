@@ -7,6 +7,11 @@ mod evenements;
 mod requetes;
 mod structure_donnees;
 mod jwt;
+mod etat_clients;
+mod entretien;
+mod verification_client;
+mod periodes;
+mod releves;
 
 use log::info;
 use millegrilles_common_rust::tokio::runtime::Builder;
@@ -0,0 +1,28 @@
+use millegrilles_common_rust::chrono::{Datelike, DateTime, TimeZone, Utc};
+use millegrilles_common_rust::error::Error;
+
+/// Debut (minuit UTC) de la journee qui contient la date.
+pub fn debut_jour(date: &DateTime<Utc>) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.date_naive().and_hms_opt(0, 0, 0).expect("and_hms_opt minuit"))
+}
+
+/// Debut (UTC) du mois indique, mois de 1 a 12.
+pub fn debut_mois(annee: i32, mois: u32) -> Result<DateTime<Utc>, Error> {
+    match Utc.with_ymd_and_hms(annee, mois, 1, 0, 0, 0).single() {
+        Some(inner) => Ok(inner),
+        None => Err(Error::String(format!("debut_mois Mois invalide : {}-{}", annee, mois)))
+    }
+}
+
+/// Debut du mois qui suit le mois indique.
+pub fn debut_mois_suivant(annee: i32, mois: u32) -> Result<DateTime<Utc>, Error> {
+    match mois {
+        12 => debut_mois(annee + 1, 1),
+        _ => debut_mois(annee, mois + 1)
+    }
+}
+
+/// Identifiant du mois de la date, format YYYY-MM.
+pub fn format_mois(date: &DateTime<Utc>) -> String {
+    format!("{:04}-{:02}", date.year(), date.month())
+}
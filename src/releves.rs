@@ -0,0 +1,285 @@
+use log::{debug, error};
+use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::chrono;
+use millegrilles_common_rust::chrono::{Datelike, DateTime, Utc};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::mongo_dao::{convertir_bson_deserializable, MongoDao};
+use millegrilles_common_rust::tokio_stream::StreamExt;
+use serde::Deserialize;
+
+use crate::constantes;
+use crate::etat_clients::{charger_plan, fusionner_quota};
+use crate::periodes::{debut_jour, debut_mois, debut_mois_suivant};
+use crate::structure_donnees::{CalculReleve, ClientHebergementRow, RenouvellementRow, UtilisationJourRow};
+
+const OCTETS_GO: f64 = 1_000_000_000.0;
+
+/// Arrondi d'un montant en cents.
+fn arrondir_montant(montant: f64) -> i64 {
+    montant.round() as i64
+}
+
+/// Periodes sans hebergement entre deux renouvellements (le client etait expire lors du renouvellement).
+async fn charger_interruptions<M>(middleware: &M, idmg: &str, debut: &DateTime<Utc>, fin: &DateTime<Utc>)
+    -> Result<Vec<(DateTime<Utc>, DateTime<Utc>)>, Error>
+    where M: MongoDao
+{
+    let filtre = doc! {
+        "idmg": idmg,
+        "expiration_precedente": {"$lt": *fin},
+        "debut": {"$gt": *debut},
+    };
+    let collection = middleware.get_collection_typed::<RenouvellementRow>(constantes::COLLECTION_RENOUVELLEMENTS_NOM)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut interruptions = Vec::new();
+    while curseur.advance().await? {
+        match curseur.deserialize_current() {
+            Ok(inner) => {
+                if let Some(expiration_precedente) = inner.expiration_precedente {
+                    if expiration_precedente < inner.debut {
+                        interruptions.push((expiration_precedente, inner.debut));
+                    }
+                }
+            },
+            Err(e) => error!("charger_interruptions Erreur mapping renouvellement, skip : {:?}", e)
+        }
+    }
+    Ok(interruptions)
+}
+
+#[derive(Deserialize)]
+struct TransfertsPeriode {
+    octets_download: i64,
+}
+
+/// Total des octets transferts vers le client (download) rapportes par toutes les instances de
+/// consignation pour la periode [debut, fin).
+async fn charger_octets_download<M>(middleware: &M, idmg: &str, debut: &DateTime<Utc>, fin: &DateTime<Utc>)
+    -> Result<i64, Error>
+    where M: MongoDao
+{
+    let pipeline = vec![
+        doc! {"$match": {"idmg": idmg, "jour": {"$gte": *debut, "$lt": *fin}}},
+        doc! {"$group": {"_id": "$idmg", "octets_download": {"$sum": "$octets_download"}}},
+    ];
+    let collection = middleware.get_collection(constantes::COLLECTION_TRANSFERTS_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    match curseur.next().await {
+        Some(resultat) => {
+            let transferts: TransfertsPeriode = convertir_bson_deserializable(resultat?)?;
+            Ok(transferts.octets_download)
+        },
+        None => Ok(0)
+    }
+}
+
+/// Nombre de jours du mois durant lesquels le client etait heberge. Un jour est actif si son debut
+/// est apres la creation du client, avant l'expiration courante et hors des interruptions.
+fn compter_jours_actifs(client: &ClientHebergementRow, interruptions: &Vec<(DateTime<Utc>, DateTime<Utc>)>,
+                        debut: &DateTime<Utc>, fin: &DateTime<Utc>) -> i64
+{
+    let creation = client.creation.as_ref().map(debut_jour);
+    let mut jours_actifs = 0;
+    let mut jour = *debut;
+    while jour < *fin {
+        let apres_creation = creation.map(|c| jour >= c).unwrap_or(true);
+        let avant_expiration = client.expiration.map(|e| jour < e).unwrap_or(true);
+        let interrompu = interruptions.iter().any(|(d, f)| jour >= *d && jour < *f);
+        if apres_creation && avant_expiration && ! interrompu {
+            jours_actifs += 1;
+        }
+        jour = jour + chrono::Duration::days(1);
+    }
+    jours_actifs
+}
+
+/// Montants d'un client pour une periode d'un mois.
+struct MontantsPeriode {
+    jours_actifs: i64,
+    montant_plan: i64,
+    go_depassement: f64,
+    montant_depassement: i64,
+    octets_download: i64,
+    montant_transfert: i64,
+}
+
+impl MontantsPeriode {
+    fn total(&self) -> i64 {
+        self.montant_plan + self.montant_depassement + self.montant_transfert
+    }
+}
+
+/// Calcule les montants d'un client pour la periode [debut, fin) d'un mois de jours_mois jours : prix du
+/// plan au prorata des jours actifs, depassement de l'espace a partir des releves d'utilisation
+/// journaliere et transferts (download) rapportes.
+async fn calculer_montants_periode<M>(middleware: &M, client: &ClientHebergementRow,
+                                      debut: &DateTime<Utc>, fin: &DateTime<Utc>, jours_mois: i64)
+    -> Result<MontantsPeriode, Error>
+    where M: MongoDao
+{
+    let plan = match client.plan_id.as_ref() {
+        Some(plan_id) => charger_plan(middleware, plan_id).await?,
+        None => None
+    };
+
+    let interruptions = charger_interruptions(middleware, client.idmg.as_str(), debut, fin).await?;
+    let jours_actifs = compter_jours_actifs(client, &interruptions, debut, fin);
+
+    let prix_mensuel = plan.as_ref().and_then(|p| p.prix_mensuel).unwrap_or(0);
+    let montant_plan = arrondir_montant(prix_mensuel as f64 * jours_actifs as f64 / jours_mois as f64);
+
+    // Depassement de l'espace a partir de l'utilisation journaliere
+    let taille_max = fusionner_quota(plan.as_ref().and_then(|p| p.quota.clone()), client)
+        .and_then(|q| q.taille_max);
+    let filtre = doc! {"idmg": &client.idmg, "jour": {"$gte": *debut, "$lt": *fin}};
+    let collection = middleware.get_collection_typed::<UtilisationJourRow>(constantes::COLLECTION_UTILISATION_JOURNALIERE_NOM)?;
+    let mut curseur = collection.find(filtre, None).await?;
+    let mut octets_depassement_jours = 0i64;
+    while curseur.advance().await? {
+        let utilisation = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("calculer_montants_periode Erreur mapping utilisation journaliere, skip : {:?}", e);
+                continue
+            }
+        };
+        if let Some(taille_max) = taille_max {
+            octets_depassement_jours += (utilisation.taille_totale - taille_max).max(0);
+        }
+    }
+
+    let go_depassement = octets_depassement_jours as f64 / OCTETS_GO / jours_mois as f64;
+    let prix_depassement_go = plan.as_ref().and_then(|p| p.prix_depassement_go).unwrap_or(0);
+    let montant_depassement = arrondir_montant(go_depassement * prix_depassement_go as f64);
+
+    // Transferts a partir des rapports des instances de consignation (pas des releves journaliers,
+    // qui peuvent manquer les rapports recus apres la derniere passe d'entretien de la journee)
+    let octets_download = charger_octets_download(middleware, client.idmg.as_str(), debut, fin).await?;
+    let prix_transfert_go = plan.as_ref().and_then(|p| p.prix_transfert_go).unwrap_or(0);
+    let montant_transfert = arrondir_montant(octets_download as f64 / OCTETS_GO * prix_transfert_go as f64);
+
+    Ok(MontantsPeriode {
+        jours_actifs,
+        montant_plan,
+        go_depassement,
+        montant_depassement,
+        octets_download,
+        montant_transfert,
+    })
+}
+
+/// Calcule le releve d'un client pour un mois.
+pub async fn calculer_releve<M>(middleware: &M, client: &ClientHebergementRow, annee: i32, mois: u32)
+    -> Result<CalculReleve, Error>
+    where M: MongoDao
+{
+    let debut = debut_mois(annee, mois)?;
+    let fin = debut_mois_suivant(annee, mois)?;
+    let jours_mois = (fin - debut).num_days();
+
+    let montants = calculer_montants_periode(middleware, client, &debut, &fin, jours_mois).await?;
+
+    let releve = CalculReleve {
+        idmg: client.idmg.clone(),
+        mois: format!("{:04}-{:02}", annee, mois),
+        plan_id: client.plan_id.clone(),
+        jours_actifs: montants.jours_actifs,
+        jours_mois,
+        montant_plan: montants.montant_plan,
+        go_depassement: montants.go_depassement,
+        montant_depassement: montants.montant_depassement,
+        octets_download: montants.octets_download,
+        montant_transfert: montants.montant_transfert,
+        montant_total: montants.total(),
+    };
+    debug!("calculer_releve Client {} mois {} : total {}", releve.idmg, releve.mois, releve.montant_total);
+
+    Ok(releve)
+}
+
+/// Calcule la consommation (cents) d'un client pour la journee qui contient la date. La somme des
+/// consommations journalieres peut differer du releve du mois (arrondis, rapports en retard), la
+/// difference est ajustee lors de la finalisation du releve.
+pub async fn calculer_consommation_jour<M>(middleware: &M, client: &ClientHebergementRow, jour: &DateTime<Utc>)
+    -> Result<i64, Error>
+    where M: MongoDao
+{
+    let debut = debut_jour(jour);
+    let fin = debut + chrono::Duration::days(1);
+    let jours_mois = (debut_mois_suivant(debut.year(), debut.month())? - debut_mois(debut.year(), debut.month())?).num_days();
+
+    let montants = calculer_montants_periode(middleware, client, &debut, &fin, jours_mois).await?;
+    debug!("calculer_consommation_jour Client {} jour {:?} : total {}", client.idmg, debut, montants.total());
+
+    Ok(montants.total())
+}
+
+/// Echappe une valeur CSV (guillemets si la valeur contient une virgule, un guillemet ou un saut de ligne).
+fn echapper_csv(valeur: &str) -> String {
+    if valeur.contains(',') || valeur.contains('"') || valeur.contains('\n') {
+        format!("\"{}\"", valeur.replace('"', "\"\""))
+    } else {
+        valeur.to_string()
+    }
+}
+
+pub const ENTETE_CSV_RELEVES: &str = "idmg,mois,plan_id,etat,jours_actifs,jours_mois,montant_plan,go_depassement,montant_depassement,octets_download,montant_transfert,montant_total";
+
+/// Ligne CSV d'un releve, colonnes de ENTETE_CSV_RELEVES.
+pub fn formatter_ligne_csv(releve: &CalculReleve, etat: &str) -> String {
+    format!("{},{},{},{},{},{},{},{:.3},{},{},{},{}",
+            echapper_csv(releve.idmg.as_str()),
+            releve.mois,
+            echapper_csv(releve.plan_id.as_deref().unwrap_or("")),
+            etat,
+            releve.jours_actifs,
+            releve.jours_mois,
+            releve.montant_plan,
+            releve.go_depassement,
+            releve.montant_depassement,
+            releve.octets_download,
+            releve.montant_transfert,
+            releve.montant_total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use millegrilles_common_rust::chrono::TimeZone;
+
+    fn date(jour: u32, heure: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, jour, heure, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_compter_jours_actifs_mois_complet() {
+        let debut = debut_mois(2026, 3).unwrap();
+        let fin = debut_mois_suivant(2026, 3).unwrap();
+        assert_eq!(31, compter_jours_actifs(&ClientHebergementRow::nouveau_test(), &vec![], &debut, &fin));
+    }
+
+    #[test]
+    fn test_compter_jours_actifs_mois_partiel() {
+        let debut = debut_mois(2026, 3).unwrap();
+        let fin = debut_mois_suivant(2026, 3).unwrap();
+
+        // Creation en cours de journee : la journee de creation est active
+        let client_partiel = ClientHebergementRow { creation: Some(date(10, 14)), expiration: Some(date(20, 0)), ..ClientHebergementRow::nouveau_test() };
+        assert_eq!(10, compter_jours_actifs(&client_partiel, &vec![], &debut, &fin));
+
+        // Expiration en cours de journee : la journee d'expiration est active
+        let client_expiration = ClientHebergementRow { creation: Some(date(10, 14)), expiration: Some(date(20, 12)), ..ClientHebergementRow::nouveau_test() };
+        assert_eq!(11, compter_jours_actifs(&client_expiration, &vec![], &debut, &fin));
+
+        let interruptions = vec![(date(12, 0), date(14, 0))];
+        assert_eq!(8, compter_jours_actifs(&client_partiel, &interruptions, &debut, &fin));
+    }
+
+    #[test]
+    fn test_echapper_csv() {
+        assert_eq!("simple", echapper_csv("simple"));
+        assert_eq!("\"a,b\"", echapper_csv("a,b"));
+        assert_eq!("\"dit \"\"bonjour\"\"\"", echapper_csv("dit \"bonjour\""));
+        assert_eq!("\"ligne\nsuivante\"", echapper_csv("ligne\nsuivante"));
+    }
+}
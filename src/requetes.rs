@@ -1,29 +1,38 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::str::from_utf8;
 use log::{debug, error, warn};
-use millegrilles_common_rust::bson::{Bson, doc};
+use millegrilles_common_rust::bson::{Bson, doc, Document};
 use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
 use millegrilles_common_rust::chrono::{DateTime, Utc};
-use millegrilles_common_rust::common_messages::RequeteDechiffrage;
+use millegrilles_common_rust::base64::{Engine as _, engine::general_purpose::STANDARD_NO_PAD as base64_nopad};
+use millegrilles_common_rust::common_messages::{ReponseRequeteDechiffrageV2, RequeteDechiffrage};
 use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, RolesCertificats, Securite, CHAMP_MODIFICATION, CHAMP_CREATION, DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2};
-use millegrilles_common_rust::dechiffrage::DataChiffre;
+use millegrilles_common_rust::dechiffrage::{DataChiffre, dechiffrer_data_secrete};
 use millegrilles_common_rust::error::Error;
 use millegrilles_common_rust::generateur_messages::{GenerateurMessages, RoutageMessageAction};
 use millegrilles_common_rust::middleware::MiddlewareMessages;
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{MessageMilleGrillesBufferDefault, MessageMilleGrillesOwned, MessageValidable, optionepochseconds};
-use millegrilles_common_rust::mongo_dao::MongoDao;
+use millegrilles_common_rust::mongo_dao::{chrono_datetime_as_bson_datetime, convertir_bson_deserializable, MongoDao};
 use millegrilles_common_rust::rabbitmq_dao::TypeMessageOut;
-use millegrilles_common_rust::recepteur_messages::MessageValide;
+use millegrilles_common_rust::recepteur_messages::{MessageValide, TypeMessage};
 use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::epochseconds;
+use millegrilles_common_rust::millegrilles_cryptographie::x25519::CleSecreteX25519;
 use millegrilles_common_rust::millegrilles_cryptographie::x509::EnveloppeCertificat;
+use millegrilles_common_rust::serde_json;
+use millegrilles_common_rust::serde_json::{Map, Value};
 use millegrilles_common_rust::mongodb::options::FindOptions;
+use millegrilles_common_rust::tokio_stream::StreamExt;
 
 use serde::{Deserialize, Serialize};
 use crate::constantes;
 use crate::constantes::COLLECTION_CLIENTS_NOM;
 use crate::domaine_hebergement::GestionnaireDomaineHebergement;
-use crate::jwt::generer_jwt_hebergement;
-use crate::structure_donnees::{ClientHebergementRow, QuotaClient};
+use crate::etat_clients::{charger_quota_client, charger_quotas_plans, charger_solde_client, charger_solde_prepaye, charger_utilisation_client, charger_utilisation_clients, determiner_etat_acces, EtatAccesClient, fusionner_quota, get_fin_acces, quota_depasse, RaisonRefus};
+use crate::jwt::{generer_jwt_hebergement, LimitesTokenHebergement};
+use crate::periodes::{debut_mois, debut_mois_suivant};
+use crate::releves::{ENTETE_CSV_RELEVES, formatter_ligne_csv};
+use crate::structure_donnees::{ClientHebergementRow, DataClientDechiffre, DemandeHebergementRow, EcritureGrandLivreRow, PlanHebergementRow, QuotaClient, ReleveRow, ServiceCatalogueRow, UtilisationClient};
+use crate::verification_client::{ResultatVerificationClient, verifier_acces_operateur, verifier_requete_client};
 
 pub async fn consommer_requete<M>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -41,6 +50,18 @@ pub async fn consommer_requete<M>(gestionnaire: &GestionnaireDomaineHebergement,
         // Commandes standard
         constantes::REQUETE_LISTE_CLIENTS => requete_liste_clients(gestionnaire, middleware, message).await,
         constantes::REQUETE_TOKEN_JWT => requete_token_jwt(gestionnaire, middleware, message).await,
+        constantes::REQUETE_CLIENT => requete_client(gestionnaire, middleware, message).await,
+        constantes::REQUETE_STATUT_HEBERGEMENT => requete_statut_hebergement(gestionnaire, middleware, message).await,
+        constantes::REQUETE_LISTE_DEMANDES => requete_liste_demandes(gestionnaire, middleware, message).await,
+        constantes::REQUETE_HISTORIQUE_CLIENT => requete_historique_client(gestionnaire, middleware, message).await,
+        constantes::REQUETE_CATALOGUE => requete_catalogue(gestionnaire, middleware, message).await,
+        constantes::REQUETE_LISTE_PLANS => requete_liste_plans(gestionnaire, middleware, message).await,
+        constantes::REQUETE_UTILISATION_CLIENT => requete_utilisation_client(gestionnaire, middleware, message).await,
+        constantes::REQUETE_UTILISATION_CLIENTS => requete_utilisation_clients(gestionnaire, middleware, message).await,
+        constantes::REQUETE_TRANSFERTS_MENSUELS => requete_transferts_mensuels(gestionnaire, middleware, message).await,
+        constantes::REQUETE_HISTORIQUE_UTILISATION => requete_historique_utilisation(gestionnaire, middleware, message).await,
+        constantes::REQUETE_EXPORTER_RELEVES => requete_exporter_releves(gestionnaire, middleware, message).await,
+        constantes::REQUETE_GRAND_LIVRE_CLIENT => requete_grand_livre_client(gestionnaire, middleware, message).await,
 
         // Commande inconnue
         _ => Err(Error::String(format!("consommer_requete: Requete {} inconnue, **DROPPED**\n{}",
@@ -72,7 +93,63 @@ fn verifier_autorisation(message: &MessageValide) -> Result<(), Error> {
 // Requetes
 // ********
 #[derive(Deserialize)]
-struct RequeteListeClients {}
+struct RequeteListeClients {
+    skip: Option<u64>,
+    limit: Option<i64>,
+    /// Champ de tri : creation (defaut), modification ou expiration.
+    tri: Option<String>,
+    /// Ordre du tri : 1 (defaut) ou -1.
+    ordre: Option<i32>,
+    actif: Option<bool>,
+    #[serde(default, with = "optionepochseconds")]
+    expiration_min: Option<DateTime<Utc>>,
+    #[serde(default, with = "optionepochseconds")]
+    expiration_max: Option<DateTime<Utc>>,
+    /// Clients qui hebergent au moins un des roles.
+    roles: Option<Vec<String>>,
+    /// Clients qui hebergent au moins un des domaines.
+    domaines: Option<Vec<String>>,
+}
+
+impl RequeteListeClients {
+    fn get_filtre(&self) -> Document {
+        let mut filtre = doc! {};
+        if let Some(actif) = self.actif {
+            filtre.insert("actif", actif);
+        }
+        if self.expiration_min.is_some() || self.expiration_max.is_some() {
+            let mut filtre_expiration = doc! {};
+            if let Some(inner) = self.expiration_min {
+                filtre_expiration.insert("$gte", inner);
+            }
+            if let Some(inner) = self.expiration_max {
+                filtre_expiration.insert("$lte", inner);
+            }
+            filtre.insert("expiration", filtre_expiration);
+        }
+        if let Some(inner) = self.roles.as_ref() {
+            filtre.insert("roles", doc! {"$in": inner.clone()});
+        }
+        if let Some(inner) = self.domaines.as_ref() {
+            filtre.insert("domaines", doc! {"$in": inner.clone()});
+        }
+        filtre
+    }
+
+    fn get_tri(&self) -> Result<Document, Error> {
+        let champ = match self.tri.as_ref().map(|t| t.as_str()) {
+            None | Some("creation") => CHAMP_CREATION,
+            Some("modification") => CHAMP_MODIFICATION,
+            Some("expiration") => "expiration",
+            Some(inner) => Err(Error::String(format!("RequeteListeClients Tri {} non supporte", inner)))?
+        };
+        let ordre = match self.ordre {
+            Some(-1) => -1,
+            _ => 1
+        };
+        Ok(doc! {champ: ordre, "_id": ordre})
+    }
+}
 
 #[derive(Serialize)]
 struct ReponseClientRow {
@@ -91,6 +168,14 @@ struct ReponseClientRow {
     expiration: Option<DateTime<Utc>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     quota: Option<QuotaClient>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plan_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actif: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suspendu: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prepaye: Option<bool>,
 }
 
 #[derive(Serialize)]
@@ -98,6 +183,7 @@ struct ReponseListeClients {
     ok: bool,
     err: Option<String>,
     clients: Vec<ReponseClientRow>,
+    total: u64,
 }
 
 impl From<ClientHebergementRow> for ReponseClientRow {
@@ -111,6 +197,10 @@ impl From<ClientHebergementRow> for ReponseClientRow {
             information: value.information,
             expiration: value.expiration,
             quota: value.quota,
+            plan_id: value.plan_id,
+            actif: value.actif,
+            suspendu: value.suspendu,
+            prepaye: value.prepaye,
         }
     }
 }
@@ -121,37 +211,38 @@ async fn requete_liste_clients<M>(_gestionnaire: &GestionnaireDomaineHebergement
 {
     debug!("requete_liste_clients Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
 
-    let est_delegation_globale = message.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)?;
-    if est_delegation_globale {
-        // Ok
-    } else if message.certificat.verifier_exchanges(vec![Securite::L3Protege, Securite::L4Secure])? {
-        // Ok
-    } else {
+    if ! verifier_acces_operateur(&message)? {
         Err(Error::Str("requete_liste_clients Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
     }
 
     let message_ref = message.message.parse()?;
     let requete: RequeteListeClients = message_ref.contenu()?.deserialize()?;
 
-    // let skip = requete.skip.unwrap_or_else(|| 0);
-    // let limit = requete.limit.unwrap_or_else(|| 1000);
+    let skip = requete.skip.unwrap_or_else(|| 0);
+    let limit = requete.limit.unwrap_or_else(|| 100).clamp(1, 1000);
 
     let user_id = match message.certificat.get_user_id()? {
         Some(inner) => inner,
         None => Err(Error::Str("requete_sync_messages Certificat sans user_id"))?
     };
 
-    let filtre = doc! {};
+    let tri = match requete.get_tri() {
+        Ok(inner) => inner,
+        Err(e) => {
+            debug!("requete_liste_clients Tri invalide : {:?}", e);
+            return Ok(Some(middleware.reponse_err(Some(1), None, Some("Tri non supporte"))?))
+        }
+    };
+    let filtre = requete.get_filtre();
     let options = FindOptions::builder()
-        // .skip(skip)
-        // .limit(limit)
-        // .projection(doc!{"message_id": 1, CHAMP_MODIFICATION: 1, "supprime": 1, "date_traitement": 1})
-        // .sort(doc!{CHAMP_CREATION: 1, "_id": 1})
+        .skip(skip)
+        .limit(limit)
+        .sort(tri)
         .build();
     let collection = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let total = collection.count_documents(filtre.clone(), None).await?;
     let mut curseur = collection.find(filtre, options).await?;
-    // let mut resultat = Vec::with_capacity(limit as usize);
-    let mut resultat = Vec::new();
+    let mut resultat = Vec::with_capacity(limit as usize);
     while curseur.advance().await? {
         let row = match curseur.deserialize_current() {
             Ok(inner) => inner,
@@ -169,6 +260,7 @@ async fn requete_liste_clients<M>(_gestionnaire: &GestionnaireDomaineHebergement
         ok: true,
         err: None,
         clients: resultat,
+        total,
     };
 
     Ok(Some(middleware.build_reponse(reponse)?.0))
@@ -186,6 +278,9 @@ struct ReponseTokenJwt {
     err: Option<String>,
     jwt_readonly: Option<String>,
     jwt_readwrite: Option<String>,
+    /// Raison de l'acces degrade (lecture seule) : expire, quota, suspendu ou solde.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raison: Option<String>,
 }
 
 async fn requete_token_jwt<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
@@ -195,95 +290,1145 @@ async fn requete_token_jwt<M>(_gestionnaire: &GestionnaireDomaineHebergement, mi
     debug!("requete_liste_clients Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
     let message_ref = message.message.parse()?;
     let requete: RequeteTokenJwt = message_ref.contenu()?.deserialize()?;
-    let mut requete_client = requete.requete;
-    if ! requete_client.verifier_signature().is_ok() {
-        debug!("requete_token_jwt Signature invalide");
-        return Ok(Some(middleware.reponse_err(Some(1), None, Some("Signature requete invalide"))?))
+    // Verification d'origine (sans action ni estampille), les instances deployees signent cette requete sans routage fixe
+    let requete_client = match verifier_requete_client(middleware, requete.requete, None).await? {
+        ResultatVerificationClient::Valide(inner) => inner,
+        ResultatVerificationClient::Refusee(reponse) => return Ok(Some(reponse))
     };
+    let idmg = requete_client.idmg;
+    let enveloppe_requete = requete_client.enveloppe;
 
-    debug!("requete_liste_clients Charger enveloppe IDMG");
-    let (enveloppe_idmg, ca_pem) = match requete_client.millegrille.clone() {
-        Some(ca_pem) => {
-            match middleware.charger_enveloppe(&vec![ca_pem.clone()], None, None).await {
-                Ok(inner) => (inner, ca_pem),
-                Err(e) => {
-                    debug!("requete_token_jwt Certificat IDMG invalide : {:?}", e);
-                    return Ok(Some(middleware.reponse_err(Some(4), None, Some("Certificat IDMG invalide"))?))
-                }
-            }
-        },
+    // Verifier la delegation pour ce IDMG
+    let filtre = doc!{"idmg": &idmg};
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(COLLECTION_CLIENTS_NOM)?;
+    let doc_hebergement = match collection.find_one(filtre, None).await? {
+        Some(inner) => inner,
         None => {
-            debug!("requete_token_jwt Certificat IDMG manquant");
-            return Ok(Some(middleware.reponse_err(Some(3), None, Some("Certificat IDMG manquant"))?))
+            debug!("requete_token_jwt Hebergement non disponible pour {}", idmg);
+            return Ok(Some(middleware.reponse_err(Some(9), None, Some("Hebergement non configure pour client"))?))
         }
     };
 
-    debug!("requete_token_jwt Verifier enveloppe requete");
-    let enveloppe_requete = match requete_client.certificat.as_ref() {
-        Some(inner) => {
-            match middleware.charger_enveloppe(inner, None, Some(ca_pem.as_str())).await {
-                Ok(inner) => inner,
-                Err(e) => {
-                    debug!("requete_token_jwt Certificat requete invalide : {:?}", e);
-                    return Ok(Some(middleware.reponse_err(Some(7), None, Some("Certificat requete invalide"))?))
-                }
-            }
+    // Determiner l'etat d'acces (actif, expiration, grace, quota, suspension)
+    let quota = charger_quota_client(middleware, &doc_hebergement).await?;
+    let utilisation = charger_utilisation_client(middleware, idmg.as_str()).await?;
+    let solde = charger_solde_prepaye(middleware, &doc_hebergement).await?;
+    let etat_acces = determiner_etat_acces(&doc_hebergement, quota.as_ref(), &utilisation, solde, &Utc::now());
+    let raison = match etat_acces {
+        EtatAccesClient::Normal => None,
+        EtatAccesClient::LectureSeule(raison) => Some(raison),
+        EtatAccesClient::Refuse(RaisonRefus::Inactif) => {
+            debug!("requete_token_jwt Hebergement inactif pour {}", idmg);
+            return Ok(Some(middleware.reponse_err(Some(10), None, Some("Hebergement inactif pour client"))?))
         },
-        None => {
-            debug!("requete_token_jwt Certificat requete manquant");
-            return Ok(Some(middleware.reponse_err(Some(6), None, Some("Certificat requete manquant"))?))
+        EtatAccesClient::Refuse(RaisonRefus::Expire) => {
+            debug!("requete_token_jwt Hebergement expire depuis {:?} pour {}", doc_hebergement.expiration, idmg);
+            return Ok(Some(middleware.reponse_err(Some(11), None, Some("Hebergement expire pour client"))?))
         }
     };
+    let fin_acces = get_fin_acces(&doc_hebergement, &etat_acces);
+    let limites = LimitesTokenHebergement::new(quota.as_ref(), &utilisation, doc_hebergement.expiration);
 
-    let certificat_requete_valide = middleware.valider_chaine(
-        enveloppe_requete.as_ref(), Some(enveloppe_idmg.as_ref()), true)?;
-    if ! certificat_requete_valide {
-        debug!("requete_token_jwt Certificat requete invalide");
-        return Ok(Some(middleware.reponse_err(Some(9), None, Some("Certificat requete invalide"))?))
-    }
+    let roles_heberges = doc_hebergement.roles;
+    let domaines_heberges = doc_hebergement.domaines;
 
-    debug!("requete_liste_clients Verifier enveloppe requete");
-    if requete_client.pubkey.as_str() != enveloppe_requete.fingerprint()?.as_str() {
-        return Ok(Some(middleware.reponse_err(Some(8), None, Some("Mismatch certificat requete"))?))
-    }
+    // Generer les JWT. En mode degrade, seul le token readonly est emis.
+    let jwt_readonly = generer_jwt_hebergement(middleware, &idmg, false, roles_heberges.clone(), domaines_heberges.clone(), fin_acces.clone(), limites.clone())?;
+    let jwt_readwrite = match raison {
+        Some(raison) => {
+            debug!("requete_token_jwt Acces lecture seule pour {} (raison : {:?})", idmg, raison);
+            None
+        },
+        None => Some(generer_jwt_hebergement(middleware, &idmg, true, roles_heberges, domaines_heberges, fin_acces, limites)?)
+    };
 
-    if !enveloppe_requete.verifier_roles_string(vec!["core".to_string()])? {
-        Err(Error::Str("requete_token_jwt Seul le role core est supporte"))?
-    }
-    if !enveloppe_requete.verifier_exchanges(vec![Securite::L4Secure])? {
-        Err(Error::Str("requete_token_jwt Seul le niveau 4.secure est supporte"))?
-    }
+    let reponse = ReponseTokenJwt {
+        ok: true,
+        err: None,
+        jwt_readonly: Some(jwt_readonly),
+        jwt_readwrite,
+        raison: raison.map(|r| r.as_str().to_string()),
+    };
 
-    let idmg = enveloppe_idmg.calculer_idmg()?;
-    if enveloppe_requete.idmg()? != idmg.as_str() {
-        return Ok(Some(middleware.reponse_err(Some(5), None, Some("Mismatch idmg certificat/ca"))?))
-    }
+    debug!("requete_token_jwt Repondre avec message chiffre");
+    Ok(Some(middleware.build_reponse_chiffree(reponse, enveloppe_requete.as_ref())?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteStatutHebergement {
+    requete: MessageMilleGrillesOwned,
+}
+
+#[derive(Serialize)]
+struct ReponseStatutHebergement {
+    ok: bool,
+    err: Option<String>,
+    idmg: String,
+    /// Etat de l'acces : normal, lectureSeule ou refuse.
+    etat: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raison: Option<String>,
+    #[serde(default, with = "optionepochseconds", skip_serializing_if = "Option::is_none")]
+    expiration: Option<DateTime<Utc>>,
+    #[serde(default, with = "optionepochseconds", skip_serializing_if = "Option::is_none")]
+    fin_acces: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roles: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domaines: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    plan_id: Option<String>,
+    /// Quota effectif (plan et limites propres au client).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<QuotaClient>,
+    utilisation: UtilisationClient,
+    /// Solde prepaye en cents, absent si le client n'est pas prepaye.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    solde: Option<i64>,
+}
+
+/// Statut de l'hebergement pour la MilleGrille qui signe la requete (self-service).
+async fn requete_statut_hebergement<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("requete_statut_hebergement Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+    let message_ref = message.message.parse()?;
+    let requete: RequeteStatutHebergement = message_ref.contenu()?.deserialize()?;
+    let requete_client = match verifier_requete_client(middleware, requete.requete, Some(constantes::REQUETE_STATUT_HEBERGEMENT)).await? {
+        ResultatVerificationClient::Valide(inner) => inner,
+        ResultatVerificationClient::Refusee(reponse) => return Ok(Some(reponse))
+    };
+    let idmg = requete_client.idmg;
+    let enveloppe_requete = requete_client.enveloppe;
 
-    // Verifier la delegation pour ce IDMG
     let filtre = doc!{"idmg": &idmg};
     let collection = middleware.get_collection_typed::<ClientHebergementRow>(COLLECTION_CLIENTS_NOM)?;
     let doc_hebergement = match collection.find_one(filtre, None).await? {
         Some(inner) => inner,
         None => {
-            debug!("requete_token_jwt Hebergement non disponible pour {}", idmg);
+            debug!("requete_statut_hebergement Hebergement non disponible pour {}", idmg);
             return Ok(Some(middleware.reponse_err(Some(9), None, Some("Hebergement non configure pour client"))?))
         }
     };
 
-    let roles_heberges = doc_hebergement.roles;
-    let domaines_heberges = doc_hebergement.domaines;
-
-    // Generer les JWT
-    let jwt_readonly = generer_jwt_hebergement(middleware, &idmg, false, roles_heberges.clone(), domaines_heberges.clone())?;
-    let jwt_readwrite = generer_jwt_hebergement(middleware, &idmg, true, roles_heberges, domaines_heberges)?;
+    let quota = charger_quota_client(middleware, &doc_hebergement).await?;
+    let utilisation = charger_utilisation_client(middleware, idmg.as_str()).await?;
+    let solde = charger_solde_prepaye(middleware, &doc_hebergement).await?;
+    let etat_acces = determiner_etat_acces(&doc_hebergement, quota.as_ref(), &utilisation, solde, &Utc::now());
+    let fin_acces = get_fin_acces(&doc_hebergement, &etat_acces);
 
-    let reponse = ReponseTokenJwt {
+    let reponse = ReponseStatutHebergement {
         ok: true,
         err: None,
-        jwt_readonly: Some(jwt_readonly),
-        jwt_readwrite: Some(jwt_readwrite)
+        idmg,
+        etat: etat_acces.as_str().to_string(),
+        raison: etat_acces.get_raison().map(|r| r.to_string()),
+        expiration: doc_hebergement.expiration,
+        fin_acces,
+        roles: doc_hebergement.roles,
+        domaines: doc_hebergement.domaines,
+        plan_id: doc_hebergement.plan_id,
+        quota,
+        utilisation,
+        solde,
     };
 
-    debug!("requete_token_jwt Repondre avec message chiffre");
+    debug!("requete_statut_hebergement Repondre avec message chiffre");
     Ok(Some(middleware.build_reponse_chiffree(reponse, enveloppe_requete.as_ref())?.0))
 }
+
+#[derive(Deserialize)]
+struct RequeteClient {
+    idmg: String,
+}
+
+#[derive(Serialize)]
+struct ReponseClient {
+    ok: bool,
+    err: Option<String>,
+    client: Option<ReponseClientRow>,
+}
+
+async fn requete_client<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("requete_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteClient = message_ref.contenu()?.deserialize()?;
+
+    let filtre = doc! {"idmg": &requete.idmg};
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(COLLECTION_CLIENTS_NOM)?;
+    let mut client = match collection.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(Some(404), None, Some("Client inconnu"))?))
+    };
+
+    if let Some(data_chiffre) = client.data_chiffre.take() {
+        match dechiffrer_data_client(middleware, data_chiffre).await {
+            Ok(data_client) => {
+                client.contact = data_client.contact;
+                client.information = data_client.information;
+            },
+            Err(e) => {
+                error!("requete_client Erreur dechiffrage data_chiffre pour idmg {} : {:?}", requete.idmg, e);
+                return Ok(Some(middleware.reponse_err(Some(1), None, Some("Erreur dechiffrage information client"))?))
+            }
+        }
+    }
+
+    let reponse = ReponseClient {
+        ok: true,
+        err: None,
+        client: Some(client.into()),
+    };
+
+    debug!("requete_client Repondre avec message chiffre");
+    Ok(Some(middleware.build_reponse_chiffree(reponse, message.certificat.as_ref())?.0))
+}
+
+/// Recupere la cle de data_chiffre aupres de MaitreDesCles et dechiffre le contenu.
+async fn dechiffrer_data_client<M>(middleware: &M, data_chiffre: DataChiffre) -> Result<DataClientDechiffre, Error>
+    where M: GenerateurMessages
+{
+    let (cle_ids, liste_hachage_bytes) = match data_chiffre.cle_id.as_ref() {
+        Some(cle_id) => (Some(vec![cle_id.to_owned()]), None),
+        None => match data_chiffre.ref_hachage_bytes.as_ref() {
+            Some(hachage_bytes) => (None, Some(vec![hachage_bytes.to_owned()])),
+            None => Err(Error::Str("dechiffrer_data_client Aucune reference de cle (cle_id/ref_hachage_bytes)"))?
+        }
+    };
+
+    let requete = RequeteDechiffrage {
+        domaine: constantes::DOMAINE_NOM.to_string(),
+        liste_hachage_bytes,
+        cle_ids,
+        certificat_rechiffrage: None,
+        inclure_signature: None,
+    };
+    let routage = RoutageMessageAction::builder(
+        DOMAINE_NOM_MAITREDESCLES, MAITREDESCLES_REQUETE_DECHIFFRAGE_V2, vec![Securite::L3Protege])
+        .build();
+
+    let reponse = match middleware.transmettre_requete(routage, &requete).await? {
+        Some(TypeMessage::Valide(inner)) => inner,
+        _ => Err(Error::Str("dechiffrer_data_client Mauvais type de reponse de MaitreDesCles"))?
+    };
+
+    // La reponse V2 est chiffree pour le certificat du domaine
+    let reponse_ref = reponse.message.parse()?;
+    let enveloppe_signature = middleware.get_enveloppe_signature();
+    let reponse_dechiffree = reponse_ref.dechiffrer(enveloppe_signature.as_ref())?;
+    let reponse: ReponseRequeteDechiffrageV2 = serde_json::from_slice(reponse_dechiffree.as_slice())?;
+    if ! reponse.ok {
+        Err(Error::String(format!("dechiffrer_data_client Cle refusee par MaitreDesCles : {:?}", reponse.err)))?
+    }
+
+    let cle = match reponse.cles {
+        Some(mut cles) => match cles.pop() {
+            Some(inner) => inner,
+            None => Err(Error::Str("dechiffrer_data_client Aucune cle recue"))?
+        },
+        None => Err(Error::Str("dechiffrer_data_client Aucune cle recue"))?
+    };
+
+    let cle_bytes = base64_nopad.decode(cle.cle_secrete_base64.as_str())?;
+    if cle_bytes.len() != 32 {
+        Err(Error::Str("dechiffrer_data_client Taille de cle secrete invalide"))?
+    }
+    let mut cle_secrete = CleSecreteX25519 { 0: [0u8; 32] };
+    cle_secrete.0.copy_from_slice(&cle_bytes[0..32]);
+
+    let data_dechiffre = dechiffrer_data_secrete(cle_secrete, data_chiffre)?;
+    let data_client: DataClientDechiffre = serde_json::from_slice(data_dechiffre.data_dechiffre.as_slice())?;
+
+    Ok(data_client)
+}
+
+#[derive(Deserialize)]
+struct RequeteListeDemandes {
+    /// Etat des demandes : attente (defaut), approuvee ou refusee.
+    etat: Option<String>,
+    skip: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReponseListeDemandes {
+    ok: bool,
+    err: Option<String>,
+    demandes: Vec<DemandeHebergementRow>,
+}
+
+async fn requete_liste_demandes<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_liste_demandes Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_liste_demandes Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteListeDemandes = message_ref.contenu()?.deserialize()?;
+
+    let etat = requete.etat.unwrap_or_else(|| constantes::ETAT_DEMANDE_ATTENTE.to_string());
+    let skip = requete.skip.unwrap_or_else(|| 0);
+    let limit = requete.limit.unwrap_or_else(|| 100).clamp(1, 1000);
+
+    let filtre = doc! {constantes::CHAMP_ETAT: etat};
+    let options = FindOptions::builder()
+        .skip(skip)
+        .limit(limit)
+        .sort(doc!{CHAMP_CREATION: 1, "_id": 1})
+        .build();
+    let collection = middleware.get_collection_typed::<DemandeHebergementRow>(constantes::COLLECTION_DEMANDES_NOM)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut demandes = Vec::with_capacity(limit as usize);
+    while curseur.advance().await? {
+        match curseur.deserialize_current() {
+            Ok(inner) => demandes.push(inner),
+            Err(e) => error!("requete_liste_demandes Erreur mapping demande, skip : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseListeDemandes { ok: true, err: None, demandes };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+/// Actions de transaction qui modifient un client, incluses dans l'historique.
+const ACTIONS_HISTORIQUE_CLIENT: [&str; 4] = [
+    constantes::TRANSACTION_SAUVEGARDER_CLIENT,
+    constantes::TRANSACTION_MAJ_CLIENT,
+    constantes::TRANSACTION_PROLONGER_CLIENT,
+    constantes::TRANSACTION_APPROUVER_DEMANDE,
+];
+
+#[derive(Deserialize)]
+struct RequeteHistoriqueClient {
+    idmg: String,
+    skip: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct TransactionClientRow {
+    id: String,
+    pubkey: String,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    estampille: DateTime<Utc>,
+    contenu: String,
+    routage: Option<RoutageTransactionRow>,
+}
+
+#[derive(Deserialize)]
+struct RoutageTransactionRow {
+    action: Option<String>,
+}
+
+#[derive(Clone, Serialize)]
+struct SignataireTransaction {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    common_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ChangementChamp {
+    champ: String,
+    avant: Value,
+    apres: Value,
+}
+
+#[derive(Serialize)]
+struct EntreeHistoriqueClient {
+    transaction_id: String,
+    action: String,
+    #[serde(with = "epochseconds")]
+    estampille: DateTime<Utc>,
+    signataire: SignataireTransaction,
+    changements: Vec<ChangementChamp>,
+}
+
+#[derive(Serialize)]
+struct ReponseHistoriqueClient {
+    ok: bool,
+    err: Option<String>,
+    historique: Vec<EntreeHistoriqueClient>,
+}
+
+/// Applique le contenu d'une transaction sur l'etat du client et retourne les champs modifies.
+fn appliquer_transaction_historique(etat: &mut Map<String, Value>, action: &str, estampille: &DateTime<Utc>, contenu: Map<String, Value>)
+    -> Vec<ChangementChamp>
+{
+    let nouvel_etat = match action {
+        // prolongerClient ajoute la duree a l'expiration courante, ou a la date de la transaction
+        // si le client est deja expire (meme calcul que transaction_prolonger_client)
+        constantes::TRANSACTION_PROLONGER_CLIENT => {
+            let mut nouvel_etat = etat.clone();
+            let duree_jours = contenu.get("duree_jours").and_then(|v| v.as_i64()).unwrap_or(0);
+            let debut = match etat.get("expiration").and_then(|v| v.as_i64()) {
+                Some(inner) if inner > estampille.timestamp() => inner,
+                _ => estampille.timestamp()
+            };
+            nouvel_etat.insert("expiration".to_string(), Value::from(debut + duree_jours * 86400));
+            nouvel_etat
+        },
+        // approuverDemande cree le client actif a partir de la demande
+        constantes::TRANSACTION_APPROUVER_DEMANDE => {
+            let mut nouvel_etat = contenu;
+            nouvel_etat.remove("demande_id");
+            nouvel_etat.insert("actif".to_string(), Value::from(true));
+            nouvel_etat
+        },
+        // majClient modifie uniquement les champs presents (null retire le champ)
+        constantes::TRANSACTION_MAJ_CLIENT => {
+            let mut nouvel_etat = etat.clone();
+            for (champ, valeur) in contenu {
+                match valeur {
+                    Value::Null => { nouvel_etat.remove(&champ); },
+                    _ => { nouvel_etat.insert(champ, valeur); }
+                }
+            }
+            nouvel_etat
+        },
+        // sauvegarderClient remplace tous les champs du client
+        _ => contenu
+    };
+
+    let mut champs: Vec<&String> = etat.keys().chain(nouvel_etat.keys()).collect();
+    champs.sort();
+    champs.dedup();
+
+    let mut changements = Vec::new();
+    for champ in champs {
+        if champ.as_str() == "idmg" { continue }
+        let avant = etat.get(champ).cloned().unwrap_or(Value::Null);
+        let apres = nouvel_etat.get(champ).cloned().unwrap_or(Value::Null);
+        if avant != apres {
+            changements.push(ChangementChamp { champ: champ.to_owned(), avant, apres });
+        }
+    }
+
+    *etat = nouvel_etat;
+    changements
+}
+
+async fn charger_signataire<M>(middleware: &M, pubkey: &str) -> SignataireTransaction
+    where M: ValidateurX509
+{
+    match middleware.get_certificat(pubkey).await {
+        Some(enveloppe) => SignataireTransaction {
+            common_name: enveloppe.get_common_name().ok(),
+            user_id: enveloppe.get_user_id().unwrap_or(None),
+        },
+        None => {
+            warn!("charger_signataire Certificat {} inconnu", pubkey);
+            SignataireTransaction { common_name: None, user_id: None }
+        }
+    }
+}
+
+/// Historique des changements d'un client, reconstruit a partir des transactions du domaine.
+async fn requete_historique_client<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao + ValidateurX509
+{
+    debug!("requete_historique_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_historique_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteHistoriqueClient = message_ref.contenu()?.deserialize()?;
+    let idmg = requete.idmg;
+    let skip = requete.skip.unwrap_or_else(|| 0) as usize;
+    let limit = requete.limit.unwrap_or_else(|| 100).clamp(1, 1000) as usize;
+
+    // Le idmg est utilise dans une expression reguliere (base58, alphanumerique)
+    if idmg.is_empty() || ! idmg.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Ok(Some(middleware.reponse_err(Some(1), None, Some("idmg invalide"))?))
+    }
+
+    // Le contenu est une string json, le filtre regex elimine les transactions des autres clients.
+    // Le filtre exact sur idmg est fait apres le parsing.
+    let filtre = doc! {
+        "routage.action": {"$in": ACTIONS_HISTORIQUE_CLIENT.to_vec()},
+        "contenu": {"$regex": format!("\"idmg\"\\s*:\\s*\"{}\"", idmg)},
+    };
+    let options = FindOptions::builder()
+        .sort(doc!{"estampille": 1, "_id": 1})
+        .build();
+    let collection = middleware.get_collection_typed::<TransactionClientRow>(constantes::COLLECTION_NOM)?;
+    let mut curseur = collection.find(filtre, options).await?;
+
+    let mut etat = Map::new();
+    let mut signataires: HashMap<String, SignataireTransaction> = HashMap::new();
+    let mut historique = Vec::new();
+    let mut position = 0;
+    while curseur.advance().await? {
+        let row = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("requete_historique_client Erreur mapping transaction, skip : {:?}", e);
+                continue
+            }
+        };
+        let contenu: Map<String, Value> = match serde_json::from_str(row.contenu.as_str()) {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("requete_historique_client Erreur parsing contenu transaction {}, skip : {:?}", row.id, e);
+                continue
+            }
+        };
+        if contenu.get("idmg").and_then(|v| v.as_str()) != Some(idmg.as_str()) {
+            continue
+        }
+        let action = match row.routage.and_then(|r| r.action) {
+            Some(inner) => inner,
+            None => continue
+        };
+
+        // Les transactions precedant la page sont appliquees pour calculer les changements
+        let changements = appliquer_transaction_historique(&mut etat, action.as_str(), &row.estampille, contenu);
+        position += 1;
+        if position <= skip { continue }
+
+        let signataire = match signataires.get(&row.pubkey) {
+            Some(inner) => inner.clone(),
+            None => {
+                let signataire = charger_signataire(middleware, row.pubkey.as_str()).await;
+                signataires.insert(row.pubkey.clone(), signataire.clone());
+                signataire
+            }
+        };
+
+        historique.push(EntreeHistoriqueClient {
+            transaction_id: row.id,
+            action,
+            estampille: row.estampille,
+            signataire,
+            changements,
+        });
+        if historique.len() >= limit { break }
+    }
+
+    let reponse = ReponseHistoriqueClient { ok: true, err: None, historique };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct ReponseCatalogue {
+    ok: bool,
+    err: Option<String>,
+    services: Vec<ServiceCatalogueRow>,
+}
+
+async fn requete_catalogue<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_catalogue Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_catalogue Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc!{"type": 1, "nom": 1})
+        .build();
+    let collection = middleware.get_collection_typed::<ServiceCatalogueRow>(constantes::COLLECTION_CATALOGUE_NOM)?;
+    let mut curseur = collection.find(doc!{}, options).await?;
+    let mut services = Vec::new();
+    while curseur.advance().await? {
+        match curseur.deserialize_current() {
+            Ok(inner) => services.push(inner),
+            Err(e) => error!("requete_catalogue Erreur mapping service, skip : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseCatalogue { ok: true, err: None, services };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct ReponseListePlans {
+    ok: bool,
+    err: Option<String>,
+    plans: Vec<PlanHebergementRow>,
+}
+
+async fn requete_liste_plans<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_liste_plans Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_liste_plans Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc!{"nom": 1})
+        .build();
+    let collection = middleware.get_collection_typed::<PlanHebergementRow>(constantes::COLLECTION_PLANS_NOM)?;
+    let mut curseur = collection.find(doc!{}, options).await?;
+    let mut plans = Vec::new();
+    while curseur.advance().await? {
+        match curseur.deserialize_current() {
+            Ok(inner) => plans.push(inner),
+            Err(e) => error!("requete_liste_plans Erreur mapping plan, skip : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseListePlans { ok: true, err: None, plans };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Serialize)]
+struct UtilisationClientQuota {
+    idmg: String,
+    utilisation: UtilisationClient,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<QuotaClient>,
+    /// Pourcentage de l'espace maximal utilise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pct_taille: Option<f64>,
+    /// Pourcentage du nombre maximal de fichiers utilise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pct_fichiers: Option<f64>,
+    quota_depasse: bool,
+}
+
+impl UtilisationClientQuota {
+    fn new(idmg: String, utilisation: UtilisationClient, quota: Option<QuotaClient>) -> Self {
+        let (pct_taille, pct_fichiers, depasse) = match quota.as_ref() {
+            Some(inner) => (
+                inner.taille_max.filter(|m| *m > 0).map(|m| utilisation.taille_totale as f64 * 100.0 / m as f64),
+                inner.nombre_fichiers_max.filter(|m| *m > 0).map(|m| utilisation.nombre_fichiers as f64 * 100.0 / m as f64),
+                quota_depasse(inner, &utilisation),
+            ),
+            None => (None, None, false)
+        };
+        Self { idmg, utilisation, quota, pct_taille, pct_fichiers, quota_depasse: depasse }
+    }
+}
+
+#[derive(Deserialize)]
+struct RequeteUtilisationClient {
+    idmg: String,
+}
+
+#[derive(Serialize)]
+struct ReponseUtilisationClient {
+    ok: bool,
+    err: Option<String>,
+    #[serde(flatten)]
+    utilisation: UtilisationClientQuota,
+}
+
+async fn requete_utilisation_client<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_utilisation_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_utilisation_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteUtilisationClient = message_ref.contenu()?.deserialize()?;
+
+    let filtre = doc!{"idmg": &requete.idmg};
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(COLLECTION_CLIENTS_NOM)?;
+    let client = match collection.find_one(filtre, None).await? {
+        Some(inner) => inner,
+        None => return Ok(Some(middleware.reponse_err(Some(9), None, Some("Hebergement non configure pour client"))?))
+    };
+
+    let quota = charger_quota_client(middleware, &client).await?;
+    let utilisation = charger_utilisation_client(middleware, requete.idmg.as_str()).await?;
+
+    let reponse = ReponseUtilisationClient {
+        ok: true,
+        err: None,
+        utilisation: UtilisationClientQuota::new(requete.idmg, utilisation, quota),
+    };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteUtilisationClients {
+    /// Limiter le resultat a ces clients. Tous les clients par defaut.
+    idmgs: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+struct ReponseUtilisationClients {
+    ok: bool,
+    err: Option<String>,
+    clients: Vec<UtilisationClientQuota>,
+}
+
+async fn requete_utilisation_clients<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_utilisation_clients Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_utilisation_clients Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteUtilisationClients = message_ref.contenu()?.deserialize()?;
+
+    let filtre = match requete.idmgs.as_ref() {
+        Some(inner) => doc!{"idmg": {"$in": inner.clone()}},
+        None => doc!{}
+    };
+
+    let quotas_plans = charger_quotas_plans(middleware).await?;
+    let mut utilisation_clients = charger_utilisation_clients(middleware, filtre.clone()).await?;
+
+    let options = FindOptions::builder()
+        .sort(doc!{"idmg": 1})
+        .build();
+    let collection = middleware.get_collection_typed::<ClientHebergementRow>(COLLECTION_CLIENTS_NOM)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut clients = Vec::new();
+    while curseur.advance().await? {
+        let client = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("requete_utilisation_clients Erreur mapping client, skip : {:?}", e);
+                continue
+            }
+        };
+        let quota_plan = match client.plan_id.as_ref() {
+            Some(plan_id) => quotas_plans.get(plan_id).cloned().flatten(),
+            None => None
+        };
+        let quota = fusionner_quota(quota_plan, &client);
+        let utilisation = utilisation_clients.remove(&client.idmg).unwrap_or_default();
+        clients.push(UtilisationClientQuota::new(client.idmg, utilisation, quota));
+    }
+
+    let reponse = ReponseUtilisationClients { ok: true, err: None, clients };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteTransfertsMensuels {
+    annee: i32,
+    /// Mois de 1 a 12.
+    mois: u32,
+    /// Limiter le resultat a un client. Tous les clients par defaut.
+    idmg: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TransfertMensuelClient {
+    #[serde(rename(deserialize = "_id"))]
+    idmg: String,
+    octets_upload: i64,
+    octets_download: i64,
+    /// Nombre de journees avec un rapport de transfert.
+    jours: i64,
+}
+
+#[derive(Serialize)]
+struct ReponseTransfertsMensuels {
+    ok: bool,
+    err: Option<String>,
+    annee: i32,
+    mois: u32,
+    clients: Vec<TransfertMensuelClient>,
+}
+
+async fn requete_transferts_mensuels<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_transferts_mensuels Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_transferts_mensuels Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteTransfertsMensuels = message_ref.contenu()?.deserialize()?;
+
+    let debut = debut_mois(requete.annee, requete.mois)?;
+    let fin = debut_mois_suivant(requete.annee, requete.mois)?;
+    let mut filtre = doc!{"jour": {"$gte": debut, "$lt": fin}};
+    if let Some(idmg) = requete.idmg.as_ref() {
+        filtre.insert("idmg", idmg);
+    }
+
+    // Additionner les journees de toutes les instances de consignation
+    let pipeline = vec![
+        doc!{"$match": filtre},
+        doc!{"$group": {
+            "_id": "$idmg",
+            "octets_upload": {"$sum": "$octets_upload"},
+            "octets_download": {"$sum": "$octets_download"},
+            "jours": {"$addToSet": "$jour"},
+        }},
+        doc!{"$project": {"octets_upload": 1, "octets_download": 1, "jours": {"$size": "$jours"}}},
+        doc!{"$sort": {"_id": 1}},
+    ];
+    let collection = middleware.get_collection(constantes::COLLECTION_TRANSFERTS_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut clients = Vec::new();
+    while let Some(resultat) = curseur.next().await {
+        match convertir_bson_deserializable::<TransfertMensuelClient>(resultat?) {
+            Ok(inner) => clients.push(inner),
+            Err(e) => error!("requete_transferts_mensuels Erreur mapping transfert, skip : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseTransfertsMensuels { ok: true, err: None, annee: requete.annee, mois: requete.mois, clients };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteHistoriqueUtilisation {
+    idmg: String,
+    #[serde(with = "epochseconds")]
+    debut: DateTime<Utc>,
+    /// Maintenant par defaut.
+    #[serde(default, with = "optionepochseconds")]
+    fin: Option<DateTime<Utc>>,
+    /// jour (defaut), semaine ou mois.
+    granularite: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PeriodeUtilisation {
+    /// Debut de la periode (jour, semaine commencant le lundi ou mois).
+    #[serde(rename(deserialize = "_id"), serialize_with = "epochseconds::serialize",
+            deserialize_with = "chrono_datetime_as_bson_datetime::deserialize")]
+    debut: DateTime<Utc>,
+    /// Utilisation au dernier releve de la periode.
+    nombre_fichiers: i64,
+    taille_totale: i64,
+    /// Utilisation maximale durant la periode.
+    taille_max: i64,
+    octets_upload: i64,
+    octets_download: i64,
+}
+
+#[derive(Serialize)]
+struct ReponseHistoriqueUtilisation {
+    ok: bool,
+    err: Option<String>,
+    idmg: String,
+    granularite: String,
+    periodes: Vec<PeriodeUtilisation>,
+}
+
+async fn requete_historique_utilisation<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_historique_utilisation Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_historique_utilisation Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteHistoriqueUtilisation = message_ref.contenu()?.deserialize()?;
+
+    let granularite = requete.granularite.unwrap_or_else(|| String::from("jour"));
+    let regroupement = match granularite.as_str() {
+        "jour" => Bson::String(String::from("$jour")),
+        "semaine" => Bson::Document(doc!{"$dateTrunc": {"date": "$jour", "unit": "week", "startOfWeek": "monday"}}),
+        "mois" => Bson::Document(doc!{"$dateTrunc": {"date": "$jour", "unit": "month"}}),
+        _ => return Ok(Some(middleware.reponse_err(Some(1), None, Some("Granularite non supportee (jour, semaine ou mois)"))?))
+    };
+    let fin = requete.fin.unwrap_or_else(Utc::now);
+
+    let pipeline = vec![
+        doc!{"$match": {"idmg": &requete.idmg, "jour": {"$gte": requete.debut, "$lte": fin}}},
+        doc!{"$sort": {"jour": 1}},
+        doc!{"$group": {
+            "_id": regroupement,
+            "nombre_fichiers": {"$last": "$nombre_fichiers"},
+            "taille_totale": {"$last": "$taille_totale"},
+            "taille_max": {"$max": "$taille_totale"},
+            "octets_upload": {"$sum": "$octets_upload"},
+            "octets_download": {"$sum": "$octets_download"},
+        }},
+        doc!{"$sort": {"_id": 1}},
+    ];
+    let collection = middleware.get_collection(constantes::COLLECTION_UTILISATION_JOURNALIERE_NOM)?;
+    let mut curseur = collection.aggregate(pipeline, None).await?;
+    let mut periodes = Vec::new();
+    while let Some(resultat) = curseur.next().await {
+        match convertir_bson_deserializable::<PeriodeUtilisation>(resultat?) {
+            Ok(inner) => periodes.push(inner),
+            Err(e) => error!("requete_historique_utilisation Erreur mapping periode, skip : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseHistoriqueUtilisation { ok: true, err: None, idmg: requete.idmg, granularite, periodes };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteExporterReleves {
+    annee: i32,
+    /// Mois de 1 a 12.
+    mois: u32,
+    /// csv ou jsonl (defaut).
+    format: Option<String>,
+    /// Exporter seulement les releves finalises.
+    finalises: Option<bool>,
+}
+
+#[derive(Serialize)]
+struct ReponseExporterReleves {
+    ok: bool,
+    err: Option<String>,
+    format: String,
+    nombre_releves: usize,
+    contenu: String,
+}
+
+async fn requete_exporter_releves<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_exporter_releves Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_exporter_releves Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteExporterReleves = message_ref.contenu()?.deserialize()?;
+
+    let format = requete.format.unwrap_or_else(|| String::from("jsonl"));
+    if format != "csv" && format != "jsonl" {
+        return Ok(Some(middleware.reponse_err(Some(1), None, Some("Format non supporte (csv ou jsonl)"))?))
+    }
+
+    let mut filtre = doc!{"mois": format!("{:04}-{:02}", requete.annee, requete.mois)};
+    if requete.finalises == Some(true) {
+        filtre.insert(constantes::CHAMP_ETAT, constantes::ETAT_RELEVE_FINALISE);
+    }
+
+    let options = FindOptions::builder()
+        .sort(doc!{"idmg": 1})
+        .build();
+    let collection = middleware.get_collection_typed::<ReleveRow>(constantes::COLLECTION_RELEVES_NOM)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut lignes = Vec::new();
+    if format == "csv" {
+        lignes.push(ENTETE_CSV_RELEVES.to_string());
+    }
+    let mut nombre_releves = 0;
+    while curseur.advance().await? {
+        let releve = match curseur.deserialize_current() {
+            Ok(inner) => inner,
+            Err(e) => {
+                error!("requete_exporter_releves Erreur mapping releve, skip : {:?}", e);
+                continue
+            }
+        };
+        let ligne = match format.as_str() {
+            "csv" => formatter_ligne_csv(&releve.calcul, releve.etat.as_str()),
+            _ => serde_json::to_string(&releve)?
+        };
+        lignes.push(ligne);
+        nombre_releves += 1;
+    }
+
+    let mut contenu = lignes.join("\n");
+    if ! contenu.is_empty() {
+        contenu.push('\n');
+    }
+
+    let reponse = ReponseExporterReleves { ok: true, err: None, format, nombre_releves, contenu };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[derive(Deserialize)]
+struct RequeteGrandLivreClient {
+    idmg: String,
+    skip: Option<u64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ReponseEcritureGrandLivre {
+    transaction_id: String,
+    #[serde(rename = "type")]
+    type_ecriture: String,
+    montant: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mois: Option<String>,
+    #[serde(with = "epochseconds")]
+    date: DateTime<Utc>,
+}
+
+impl From<EcritureGrandLivreRow> for ReponseEcritureGrandLivre {
+    fn from(value: EcritureGrandLivreRow) -> Self {
+        Self {
+            transaction_id: value.transaction_id,
+            type_ecriture: value.type_ecriture,
+            montant: value.montant,
+            reference: value.reference,
+            mois: value.mois,
+            date: value.date,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReponseGrandLivreClient {
+    ok: bool,
+    err: Option<String>,
+    idmg: String,
+    /// Solde courant en cents (credits moins debits).
+    solde: i64,
+    ecritures: Vec<ReponseEcritureGrandLivre>,
+}
+
+async fn requete_grand_livre_client<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, message: MessageValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: GenerateurMessages + MongoDao
+{
+    debug!("requete_grand_livre_client Message recu {:?}\n{}", message.type_message, from_utf8(message.message.buffer.as_slice())?);
+
+    if ! verifier_acces_operateur(&message)? {
+        Err(Error::Str("requete_grand_livre_client Acces refuse (exchange doit etre 3.protege/4.secure ou certificat proprietaire"))?
+    }
+
+    let message_ref = message.message.parse()?;
+    let requete: RequeteGrandLivreClient = message_ref.contenu()?.deserialize()?;
+
+    let solde = charger_solde_client(middleware, requete.idmg.as_str()).await?;
+
+    let skip = requete.skip.unwrap_or_else(|| 0);
+    let limit = requete.limit.unwrap_or_else(|| 100).clamp(1, 1000);
+    let options = FindOptions::builder()
+        .sort(doc!{"date": -1, "_id": -1})
+        .skip(skip)
+        .limit(limit)
+        .build();
+    let filtre = doc!{"idmg": &requete.idmg};
+    let collection = middleware.get_collection_typed::<EcritureGrandLivreRow>(constantes::COLLECTION_GRAND_LIVRE_NOM)?;
+    let mut curseur = collection.find(filtre, options).await?;
+    let mut ecritures = Vec::new();
+    while curseur.advance().await? {
+        match curseur.deserialize_current() {
+            Ok(inner) => ecritures.push(inner.into()),
+            Err(e) => error!("requete_grand_livre_client Erreur mapping ecriture, skip : {:?}", e)
+        }
+    }
+
+    let reponse = ReponseGrandLivreClient { ok: true, err: None, idmg: requete.idmg, solde, ecritures };
+    Ok(Some(middleware.build_reponse(reponse)?.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use millegrilles_common_rust::chrono::TimeZone;
+
+    fn contenu(valeur: Value) -> Map<String, Value> {
+        match valeur {
+            Value::Object(inner) => inner,
+            _ => panic!("contenu doit etre un objet")
+        }
+    }
+
+    fn maintenant() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 3, 15, 12, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn test_historique_sauvegarder_client() {
+        let mut etat = Map::new();
+        let changements = appliquer_transaction_historique(&mut etat, constantes::TRANSACTION_SAUVEGARDER_CLIENT, &maintenant(),
+            contenu(serde_json::json!({"idmg": "zTestIdmg", "descriptif": "Client", "actif": true})));
+
+        // idmg n'est pas un changement
+        let champs: Vec<&str> = changements.iter().map(|c| c.champ.as_str()).collect();
+        assert_eq!(vec!["actif", "descriptif"], champs);
+        assert_eq!(Value::Null, changements[1].avant);
+        assert_eq!(Value::from("Client"), changements[1].apres);
+
+        // sauvegarderClient remplace tous les champs, un champ absent est retire
+        let changements = appliquer_transaction_historique(&mut etat, constantes::TRANSACTION_SAUVEGARDER_CLIENT, &maintenant(),
+            contenu(serde_json::json!({"idmg": "zTestIdmg", "actif": true})));
+        assert_eq!(1, changements.len());
+        assert_eq!("descriptif", changements[0].champ.as_str());
+        assert_eq!(Value::Null, changements[0].apres);
+        assert!(! etat.contains_key("descriptif"));
+    }
+
+    #[test]
+    fn test_historique_maj_client() {
+        let mut etat = contenu(serde_json::json!({"idmg": "zTestIdmg", "descriptif": "Client", "plan_id": "base", "actif": true}));
+
+        // majClient conserve les champs absents, null retire le champ
+        let changements = appliquer_transaction_historique(&mut etat, constantes::TRANSACTION_MAJ_CLIENT, &maintenant(),
+            contenu(serde_json::json!({"idmg": "zTestIdmg", "plan_id": null, "actif": true, "suspendu": true})));
+        let champs: Vec<&str> = changements.iter().map(|c| c.champ.as_str()).collect();
+        assert_eq!(vec!["plan_id", "suspendu"], champs);
+        assert_eq!(Value::from("base"), changements[0].avant);
+        assert_eq!(Value::Null, changements[0].apres);
+
+        assert_eq!(Some(&Value::from("Client")), etat.get("descriptif"));
+        assert!(! etat.contains_key("plan_id"));
+        assert_eq!(Some(&Value::from(true)), etat.get("suspendu"));
+    }
+
+    #[test]
+    fn test_historique_prolonger_client() {
+        let maintenant = maintenant().timestamp();
+        let expiration = maintenant + 10 * 86400;
+        let mut etat = contenu(serde_json::json!({"idmg": "zTestIdmg", "expiration": expiration}));
+
+        // Prolonge a partir de l'expiration courante
+        let changements = appliquer_transaction_historique(&mut etat, constantes::TRANSACTION_PROLONGER_CLIENT, &maintenant(),
+            contenu(serde_json::json!({"idmg": "zTestIdmg", "duree_jours": 30, "reference": "facture-1"})));
+        assert_eq!(1, changements.len());
+        assert_eq!("expiration", changements[0].champ.as_str());
+        assert_eq!(Value::from(expiration), changements[0].avant);
+        assert_eq!(Value::from(expiration + 30 * 86400), changements[0].apres);
+        assert!(! etat.contains_key("reference"));
+
+        // Client expire, prolonge a partir de la date de la transaction
+        let mut etat = contenu(serde_json::json!({"idmg": "zTestIdmg", "expiration": maintenant - 86400}));
+        appliquer_transaction_historique(&mut etat, constantes::TRANSACTION_PROLONGER_CLIENT, &maintenant(),
+            contenu(serde_json::json!({"idmg": "zTestIdmg", "duree_jours": 30})));
+        assert_eq!(Some(&Value::from(maintenant + 30 * 86400)), etat.get("expiration"));
+    }
+
+    #[test]
+    fn test_historique_approuver_demande() {
+        let mut etat = Map::new();
+        let changements = appliquer_transaction_historique(&mut etat, constantes::TRANSACTION_APPROUVER_DEMANDE, &maintenant(),
+            contenu(serde_json::json!({"demande_id": "DEMANDE-1", "idmg": "zTestIdmg", "descriptif": "Client"})));
+        let champs: Vec<&str> = changements.iter().map(|c| c.champ.as_str()).collect();
+        assert_eq!(vec!["actif", "descriptif"], champs);
+        assert!(! etat.contains_key("demande_id"));
+    }
+}
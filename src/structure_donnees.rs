@@ -1,12 +1,47 @@
-use millegrilles_common_rust::bson;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde::de::Error as DeError;
 
-use millegrilles_common_rust::chrono::{DateTime, Utc};
-use millegrilles_common_rust::mongo_dao::opt_chrono_datetime_as_bson_datetime;
+use millegrilles_common_rust::bson::Bson;
+use millegrilles_common_rust::chrono::{DateTime, TimeZone, Utc};
+use millegrilles_common_rust::dechiffrage::DataChiffre;
+use millegrilles_common_rust::mongo_dao::{chrono_datetime_as_bson_datetime, opt_chrono_datetime_as_bson_datetime};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct QuotaClient {
+    /// Espace total (bytes chiffres) permis pour le client.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub taille_max: Option<i64>,
+    /// Nombre maximal de fichiers heberges.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nombre_fichiers_max: Option<i64>,
+    /// Taille maximale (bytes chiffres) d'un fichier.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub taille_fichier_max: Option<i64>,
+}
 
+impl QuotaClient {
+    /// Applique les limites de remplacement (e.g. d'un client) sur les limites du plan.
+    pub fn fusionner(&self, remplacement: &QuotaClient) -> QuotaClient {
+        QuotaClient {
+            taille_max: remplacement.taille_max.or(self.taille_max),
+            nombre_fichiers_max: remplacement.nombre_fichiers_max.or(self.nombre_fichiers_max),
+            taille_fichier_max: remplacement.taille_fichier_max.or(self.taille_fichier_max),
+        }
+    }
+}
+
+/// Expiration d'un client. Les clients sauvegardes avant la conversion en date BSON ont
+/// l'expiration en epoch secondes (voir entretien::migrer_expiration_clients).
+fn deserialize_expiration_client<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+    where D: Deserializer<'de>
+{
+    match Option::<Bson>::deserialize(deserializer)? {
+        None | Some(Bson::Null) => Ok(None),
+        Some(Bson::DateTime(inner)) => Ok(Some(inner.to_chrono())),
+        Some(Bson::Int64(inner)) => Ok(Utc.timestamp_opt(inner, 0).single()),
+        Some(Bson::Int32(inner)) => Ok(Utc.timestamp_opt(inner as i64, 0).single()),
+        Some(autre) => Err(D::Error::custom(format!("expiration client invalide : {:?}", autre)))
+    }
 }
 
 #[derive(Deserialize)]
@@ -17,16 +52,247 @@ pub struct ClientHebergementRow {
     pub domaines: Option<Vec<String>>,
     pub contact: Option<String>,
     pub information: Option<String>,
-    #[serde(default, with = "opt_chrono_datetime_as_bson_datetime")]
+    #[serde(default, deserialize_with = "deserialize_expiration_client")]
     pub expiration: Option<DateTime<Utc>>,
+    /// Limites qui remplacent celles du plan.
     pub quota: Option<QuotaClient>,
+    pub plan_id: Option<String>,
+    pub data_chiffre: Option<DataChiffre>,
+    pub actif: Option<bool>,
+    pub suspendu: Option<bool>,
+    /// Client prepaye, l'acces depend du solde du grand livre.
+    pub prepaye: Option<bool>,
+    #[serde(rename = "_mg-creation", default, with = "opt_chrono_datetime_as_bson_datetime")]
+    pub creation: Option<DateTime<Utc>>,
+}
+
+#[cfg(test)]
+impl ClientHebergementRow {
+    /// Client actif sans configuration, les tests remplacent les champs requis.
+    pub fn nouveau_test() -> Self {
+        ClientHebergementRow {
+            idmg: "zTestIdmg".to_string(),
+            descriptif: None,
+            roles: None,
+            domaines: None,
+            contact: None,
+            information: None,
+            expiration: None,
+            quota: None,
+            plan_id: None,
+            data_chiffre: None,
+            actif: Some(true),
+            suspendu: None,
+            prepaye: None,
+            creation: None,
+        }
+    }
+}
+
+/// Utilisation courante de l'hebergement par un client (fichiers non retires).
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct UtilisationClient {
+    pub nombre_fichiers: i64,
+    pub taille_totale: i64,
+    /// Fichiers deja synchronises (date_sync presente, pas de sync en cours).
+    #[serde(default)]
+    pub nombre_fichiers_synchronises: i64,
+    /// Fichiers en attente de synchronisation ou en cours de sync.
+    #[serde(default)]
+    pub nombre_fichiers_attente: i64,
+}
+
+/// Contenu dechiffre de ClientHebergementRow.data_chiffre.
+#[derive(Deserialize)]
+pub struct DataClientDechiffre {
+    pub contact: Option<String>,
+    pub information: Option<String>,
+}
+
+/// Classe de contenu d'un fichier heberge.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClasseFichier {
+    Backup,
+    Media,
+    Attachment,
+}
+
+impl ClasseFichier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Backup => "backup",
+            Self::Media => "media",
+            Self::Attachment => "attachment",
+        }
+    }
 }
 
+/// Fichier heberge pour un client (collection fichiers).
 #[derive(Deserialize)]
 pub struct FichiersHeberges {
-    fuuid: String,
-    idmg: String,
-    taille_chiffre: i64,
-    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
-    derniere_reclamation: DateTime<Utc>,
+    pub fuuid: String,
+    pub idmg: String,
+    /// Taille du fichier chiffre en bytes. Absent pour les fichiers ajoutes sans taille.
+    pub taille_chiffre: Option<i64>,
+    /// Domaine source du fichier (e.g. GrosFichiers, Messagerie).
+    pub domaine: Option<String>,
+    pub classe: Option<ClasseFichier>,
+    /// Derniere confirmation de presence par la consignation.
+    #[serde(default, with = "opt_chrono_datetime_as_bson_datetime")]
+    pub date_presence: Option<DateTime<Utc>>,
+    #[serde(default, with = "opt_chrono_datetime_as_bson_datetime")]
+    pub date_sync: Option<DateTime<Utc>>,
+    pub sync_en_cours: Option<bool>,
+    /// Fichier retire (retirerFichier ou suppression du client).
+    pub retire: Option<bool>,
+    #[serde(default, with = "opt_chrono_datetime_as_bson_datetime")]
+    pub date_retrait: Option<DateTime<Utc>>,
+}
+
+/// Demande d'hebergement soumise par une MilleGrille, en attente de traitement par un operateur.
+#[derive(Serialize, Deserialize)]
+pub struct DemandeHebergementRow {
+    pub demande_id: String,
+    pub idmg: String,
+    pub descriptif: Option<String>,
+    pub roles: Option<Vec<String>>,
+    pub domaines: Option<Vec<String>>,
+    /// Etat de la demande : attente, approuvee ou refusee.
+    pub etat: String,
+    pub raison: Option<String>,
+}
+
+/// Code d'invitation qui permet d'activer un hebergement sans approbation.
+#[derive(Deserialize)]
+pub struct InvitationRow {
+    pub code: String,
+    pub plan_id: Option<String>,
+    pub roles: Option<Vec<String>>,
+    pub domaines: Option<Vec<String>>,
+    /// Expiration de l'hebergement active avec le code.
+    #[serde(default, with = "opt_chrono_datetime_as_bson_datetime")]
+    pub expiration: Option<DateTime<Utc>>,
+    /// Le code ne peut plus etre utilise apres cette date.
+    #[serde(default, with = "opt_chrono_datetime_as_bson_datetime")]
+    pub expiration_code: Option<DateTime<Utc>>,
+    pub utilisations_max: Option<i64>,
+    pub utilisations: Option<i64>,
+}
+
+/// Role ou domaine qui peut etre heberge pour un client.
+#[derive(Serialize, Deserialize)]
+pub struct ServiceCatalogueRow {
+    /// Type de service : role ou domaine.
+    #[serde(rename = "type")]
+    pub type_service: String,
+    pub nom: String,
+    pub descriptif: Option<String>,
+}
+
+/// Offre d'hebergement. Les limites du plan s'appliquent a tous les clients qui y sont associes.
+#[derive(Serialize, Deserialize)]
+pub struct PlanHebergementRow {
+    pub plan_id: String,
+    pub nom: String,
+    pub descriptif: Option<String>,
+    pub quota: Option<QuotaClient>,
+    /// Roles permis pour les clients du plan. Aucune restriction si None.
+    pub roles: Option<Vec<String>>,
+    /// Domaines permis pour les clients du plan. Aucune restriction si None.
+    pub domaines: Option<Vec<String>>,
+    /// Duree d'une periode du plan en jours.
+    pub duree_jours: Option<i64>,
+    /// Prix d'un mois complet, en cents.
+    pub prix_mensuel: Option<i64>,
+    /// Prix (cents) par Go-mois d'espace utilise au-dela de quota.taille_max.
+    pub prix_depassement_go: Option<i64>,
+    /// Prix (cents) par Go transfere vers les clients (download).
+    pub prix_transfert_go: Option<i64>,
+}
+
+/// Transfert d'un client pour une journee, tel que rapporte par une instance de consignation.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TransfertJourRow {
+    pub idmg: String,
+    /// Debut (minuit UTC) de la journee.
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub jour: DateTime<Utc>,
+    /// Instance de consignation qui a rapporte le transfert.
+    pub instance_id: String,
+    pub octets_upload: i64,
+    pub octets_download: i64,
+}
+
+/// Releve journalier de l'utilisation d'un client. Reecrit a chaque passe d'entretien de la journee.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UtilisationJourRow {
+    pub idmg: String,
+    /// Debut (minuit UTC) de la journee.
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub jour: DateTime<Utc>,
+    pub nombre_fichiers: i64,
+    pub taille_totale: i64,
+    /// Transferts rapportes par les instances de consignation, absent si aucun rapport.
+    pub octets_upload: Option<i64>,
+    pub octets_download: Option<i64>,
+}
+
+/// Renouvellement d'un client (collection renouvellements).
+#[derive(Deserialize)]
+pub struct RenouvellementRow {
+    pub idmg: String,
+    #[serde(default, with = "opt_chrono_datetime_as_bson_datetime")]
+    pub expiration_precedente: Option<DateTime<Utc>>,
+    /// Debut de la periode couverte par le renouvellement.
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub debut: DateTime<Utc>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub expiration: DateTime<Utc>,
+}
+
+/// Calcul du releve mensuel d'un client. Les montants sont en cents.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CalculReleve {
+    pub idmg: String,
+    /// Mois du releve, format YYYY-MM.
+    pub mois: String,
+    pub plan_id: Option<String>,
+    pub jours_actifs: i64,
+    pub jours_mois: i64,
+    /// Prix du plan au prorata des jours actifs.
+    pub montant_plan: i64,
+    /// Moyenne sur le mois de l'espace (Go) utilise au-dela du quota.
+    pub go_depassement: f64,
+    pub montant_depassement: i64,
+    pub octets_download: i64,
+    pub montant_transfert: i64,
+    pub montant_total: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReleveRow {
+    #[serde(flatten)]
+    pub calcul: CalculReleve,
+    /// brouillon ou finalise.
+    pub etat: String,
+}
+
+/// Ecriture du grand livre d'un client prepaye. Le solde est la somme des credits moins la somme des debits.
+#[derive(Deserialize)]
+pub struct EcritureGrandLivreRow {
+    pub transaction_id: String,
+    pub idmg: String,
+    /// credit ou debit.
+    #[serde(rename = "type")]
+    pub type_ecriture: String,
+    /// Montant en cents, jamais negatif (nul pour un ajustement de releve deja regle).
+    pub montant: i64,
+    pub reference: Option<String>,
+    /// Mois du releve debite, format YYYY-MM.
+    pub mois: Option<String>,
+    /// Journee de consommation debitee, format YYYY-MM-DD.
+    pub jour: Option<String>,
+    #[serde(with = "chrono_datetime_as_bson_datetime")]
+    pub date: DateTime<Utc>,
 }
@@ -1,6 +1,8 @@
-use millegrilles_common_rust::bson::doc;
+use millegrilles_common_rust::bson;
+use millegrilles_common_rust::bson::{doc, Document};
 use millegrilles_common_rust::certificats::{ValidateurX509};
-use millegrilles_common_rust::chrono::{DateTime, Utc};
+use millegrilles_common_rust::chrono;
+use millegrilles_common_rust::chrono::{DateTime, TimeZone, Utc};
 use millegrilles_common_rust::db_structs::TransactionValide;
 use millegrilles_common_rust::dechiffrage::DataChiffre;
 use millegrilles_common_rust::error::Error;
@@ -12,10 +14,11 @@ use millegrilles_common_rust::serde_json;
 use millegrilles_common_rust::constantes as CommonConstantes;
 use millegrilles_common_rust::mongodb::options::UpdateOptions;
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use crate::constantes;
 
 use crate::domaine_hebergement::GestionnaireDomaineHebergement;
+use crate::structure_donnees::{CalculReleve, ClasseFichier, ClientHebergementRow, PlanHebergementRow, QuotaClient, ServiceCatalogueRow};
 
 pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, transaction: T)
     -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
@@ -39,11 +42,26 @@ pub async fn aiguillage_transaction<M, T>(gestionnaire: &GestionnaireDomaineHebe
     match action.as_str() {
         constantes::TRANSACTION_SAUVEGARDER_CLIENT => transaction_sauvegarder_client(gestionnaire, middleware, transaction).await,
         constantes::TRANSACTION_AJOUTER_FICHIER => transaction_ajouter_fichier(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_RETIRER_FICHIER => transaction_retirer_fichier(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_SUPPRIMER_CLIENT => transaction_supprimer_client(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_MAJ_CLIENT => transaction_maj_client(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_PROLONGER_CLIENT => transaction_prolonger_client(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_DEMANDE_HEBERGEMENT => transaction_demande_hebergement(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_APPROUVER_DEMANDE => transaction_approuver_demande(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_REFUSER_DEMANDE => transaction_refuser_demande(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_CREER_INVITATION => transaction_creer_invitation(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_UTILISER_INVITATION => transaction_utiliser_invitation(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_AJOUTER_SERVICE_CATALOGUE => transaction_ajouter_service_catalogue(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_RETIRER_SERVICE_CATALOGUE => transaction_retirer_service_catalogue(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_SAUVEGARDER_PLAN => transaction_sauvegarder_plan(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_FINALISER_RELEVE => transaction_finaliser_releve(gestionnaire, middleware, transaction).await,
+        constantes::TRANSACTION_CREDITER_CLIENT => transaction_ecriture_grand_livre(gestionnaire, middleware, transaction, constantes::TYPE_ECRITURE_CREDIT).await,
+        constantes::TRANSACTION_DEBITER_CLIENT => transaction_ecriture_grand_livre(gestionnaire, middleware, transaction, constantes::TYPE_ECRITURE_DEBIT).await,
         _ => Err(format!("transactions.aiguillage_transaction: Transaction {} est de type non gere : {}", transaction.transaction.id, action))?
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct TransactionSauvegarderClient {
     pub idmg: String,
     pub descriptif: Option<String>,
@@ -52,7 +70,13 @@ pub struct TransactionSauvegarderClient {
     pub roles: Option<Vec<String>>,
     pub domaines: Option<Vec<String>>,
     pub data_chiffre: Option<DataChiffre>,
-    pub actif: Option<bool>
+    pub actif: Option<bool>,
+    pub suspendu: Option<bool>,
+    /// Limites qui remplacent celles du plan.
+    pub quota: Option<QuotaClient>,
+    pub plan_id: Option<String>,
+    /// Client prepaye, l'acces depend du solde du grand livre.
+    pub prepaye: Option<bool>,
 }
 
 async fn transaction_sauvegarder_client<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, transaction: TransactionValide)
@@ -60,30 +84,44 @@ async fn transaction_sauvegarder_client<M>(_gestionnaire: &GestionnaireDomaineHe
     where M: MongoDao
 {
     let message_recu: TransactionSauvegarderClient = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    sauvegarder_client(middleware, message_recu).await?;
+    Ok(None)
+}
+
+/// Cree ou remplace la configuration d'un client (upsert sur idmg).
+async fn sauvegarder_client<M>(middleware: &M, message_recu: TransactionSauvegarderClient)
+    -> Result<(), Error>
+    where M: MongoDao
+{
     let idmg = message_recu.idmg;
 
     let filtre = doc! {"idmg": &idmg};
-    let expiration = match message_recu.expiration {
-        Some(inner) => Some(inner.timestamp()),
-        None => None
-    };
     let data_chiffre = match message_recu.data_chiffre {
         Some(inner) => Some(convertir_to_bson(inner)?),
         None => None
     };
     let actif = message_recu.actif.unwrap_or_else(|| true);
+    let suspendu = message_recu.suspendu.unwrap_or_else(|| false);
+    let quota = match message_recu.quota {
+        Some(inner) => Some(convertir_to_bson(inner)?),
+        None => None
+    };
     let ops = doc!{
         "$setOnInsert": {
             // "idmg": &idmg,
             CommonConstantes::CHAMP_CREATION: Utc::now(),
         },
         "$set": {
-            "expiration": expiration,
+            "expiration": message_recu.expiration,
             "descriptif": message_recu.descriptif,
             "roles": message_recu.roles,
             "domaines": message_recu.domaines,
             "data_chiffre": data_chiffre,
             "actif": actif,
+            "suspendu": suspendu,
+            "quota": quota,
+            "plan_id": message_recu.plan_id,
+            "prepaye": message_recu.prepaye.unwrap_or(false),
         },
         "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
     };
@@ -91,13 +129,36 @@ async fn transaction_sauvegarder_client<M>(_gestionnaire: &GestionnaireDomaineHe
     let options = UpdateOptions::builder().upsert(true).build();
     collection.update_one(filtre, ops, options).await?;
 
-    Ok(None)
+    Ok(())
 }
 
 #[derive(Deserialize)]
 pub struct TransactionAjouterFichier {
     pub idmg: String,
     pub fuuid: String,
+    /// Taille du fichier chiffre en bytes.
+    pub taille_chiffre: Option<i64>,
+    /// Domaine source du fichier (e.g. GrosFichiers, Messagerie).
+    pub domaine: Option<String>,
+    pub classe: Option<ClasseFichier>,
+}
+
+impl TransactionAjouterFichier {
+    /// Operations $set pour les metadonnees presentes. Les valeurs absentes ne remplacent pas
+    /// les valeurs deja conservees.
+    pub fn get_set_metadata(&self) -> Document {
+        let mut set_ops = Document::new();
+        if let Some(inner) = self.taille_chiffre {
+            set_ops.insert(constantes::CHAMP_TAILLE_CHIFFRE, inner);
+        }
+        if let Some(inner) = self.domaine.as_ref() {
+            set_ops.insert(constantes::CHAMP_DOMAINE, inner);
+        }
+        if let Some(inner) = self.classe.as_ref() {
+            set_ops.insert(constantes::CHAMP_CLASSE, inner.as_str());
+        }
+        set_ops
+    }
 }
 
 async fn transaction_ajouter_fichier<M>(_gestionnaire: &GestionnaireDomaineHebergement,
@@ -110,18 +171,693 @@ async fn transaction_ajouter_fichier<M>(_gestionnaire: &GestionnaireDomaineHeber
     let collection = middleware.get_collection(constantes::COLLECTION_FICHIERS_NOM)?;
     let filtre = doc!{"idmg": &message_recu.idmg, "fuuid": &message_recu.fuuid};
     let options = UpdateOptions::builder().upsert(true).build();
+    let mut set_ops = message_recu.get_set_metadata();
+    set_ops.insert(constantes::CHAMP_DATE_SYNC, bson::Bson::Null);
+    set_ops.insert(constantes::CHAMP_SYNC_EN_COURS, bson::Bson::Null);
     let ops = doc!{
         "$setOnInsert": {CommonConstantes::CHAMP_CREATION: Utc::now()},
         "$currentDate": {
             CommonConstantes::CHAMP_MODIFICATION: true,
             constantes::CHAMP_DATE_PRESENCE: true,
         },
+        "$set": set_ops,
+        // Un fichier retire qui est ajoute a nouveau redevient actif
+        "$unset": {constantes::CHAMP_RETIRE: true, constantes::CHAMP_DATE_RETRAIT: true},
+    };
+    collection.update_one(filtre, ops, options).await?;
+
+    Ok(None)
+}
+
+/// Retrait de fichiers d'un client. Les fichiers sont marques retires, la consignation peut
+/// recuperer l'espace.
+#[derive(Serialize, Deserialize)]
+pub struct TransactionRetirerFichier {
+    pub idmg: String,
+    pub fuuids: Vec<String>,
+}
+
+async fn transaction_retirer_fichier<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                        middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionRetirerFichier = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {
+        "idmg": &message_recu.idmg,
+        "fuuid": {"$in": message_recu.fuuids},
+        constantes::CHAMP_RETIRE: {"$ne": true},
+    };
+    let ops = doc! {
         "$set": {
-            constantes::CHAMP_DATE_SYNC: None::<&DateTime<Utc>>,
-            constantes::CHAMP_SYNC_EN_COURS: None::<bool>,
+            constantes::CHAMP_RETIRE: true,
+            constantes::CHAMP_DATE_RETRAIT: transaction.transaction.estampille,
+        },
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_FICHIERS_NOM)?;
+    collection.update_many(filtre, ops, None).await?;
+
+    Ok(None)
+}
+
+/// Renouvellement d'un client. La duree est resolue par la commande (e.g. a partir du plan)
+/// pour que la regeneration soit deterministe.
+#[derive(Serialize, Deserialize)]
+pub struct TransactionProlongerClient {
+    pub idmg: String,
+    pub duree_jours: i64,
+    /// Reference du renouvellement (e.g. facture, paiement).
+    pub reference: Option<String>,
+    /// Plan utilise pour determiner la duree.
+    pub plan_id: Option<String>,
+}
+
+async fn transaction_prolonger_client<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionProlongerClient = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    let transaction_id = transaction.transaction.id;
+    let date_renouvellement = transaction.transaction.estampille;
+
+    let filtre = doc! {"idmg": &message_recu.idmg};
+    let collection_clients = middleware.get_collection_typed::<ClientHebergementRow>(constantes::COLLECTION_CLIENTS_NOM)?;
+    let client = match collection_clients.find_one(filtre.clone(), None).await? {
+        Some(inner) => inner,
+        None => Err(format!("transaction_prolonger_client Client {} inconnu", message_recu.idmg))?
+    };
+
+    // Prolonger a partir de l'expiration courante, ou de la date de la transaction si le client est deja expire
+    let expiration_precedente = client.expiration;
+    let debut = match expiration_precedente {
+        Some(inner) if inner > date_renouvellement => inner,
+        _ => date_renouvellement
+    };
+    let expiration = debut + chrono::Duration::days(message_recu.duree_jours);
+
+    let ops = doc! {
+        "$set": {"expiration": expiration},
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    collection_clients.update_one(filtre, ops, None).await?;
+
+    let filtre_renouvellement = doc! {"transaction_id": &transaction_id};
+    let ops = doc! {
+        "$setOnInsert": {
+            "transaction_id": &transaction_id,
+            "idmg": &message_recu.idmg,
+            "date_renouvellement": date_renouvellement,
+            "duree_jours": message_recu.duree_jours,
+            "reference": message_recu.reference,
+            "plan_id": message_recu.plan_id,
+            "expiration_precedente": expiration_precedente,
+            "debut": debut,
+            "expiration": expiration,
+        }
+    };
+    let collection_renouvellements = middleware.get_collection(constantes::COLLECTION_RENOUVELLEMENTS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection_renouvellements.update_one(filtre_renouvellement, ops, options).await?;
+
+    Ok(None)
+}
+
+/// Distingue un champ absent (None) d'un champ explicitement null (Some(None)).
+fn deserialize_present<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where T: Deserialize<'de>, D: Deserializer<'de>
+{
+    Deserialize::deserialize(deserializer).map(Some)
+}
+
+/// Mise a jour partielle d'un client. Seuls les champs presents sont modifies, un champ
+/// present avec la valeur null est retire du client.
+#[derive(Deserialize)]
+pub struct TransactionMajClient {
+    pub idmg: String,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub descriptif: Option<Option<String>>,
+    /// Expiration en epoch secondes.
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub expiration: Option<Option<i64>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub roles: Option<Option<Vec<String>>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub domaines: Option<Option<Vec<String>>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub data_chiffre: Option<Option<DataChiffre>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub actif: Option<Option<bool>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub suspendu: Option<Option<bool>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub quota: Option<Option<QuotaClient>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub plan_id: Option<Option<String>>,
+    #[serde(default, deserialize_with="deserialize_present")]
+    pub prepaye: Option<Option<bool>>,
+}
+
+impl TransactionMajClient {
+    /// Retourne les operations $set et $unset correspondant aux champs presents.
+    fn get_operations(self) -> Result<(Document, Document), Error> {
+        let mut set_ops = Document::new();
+        let mut unset_ops = Document::new();
+
+        fn appliquer<T>(set_ops: &mut Document, unset_ops: &mut Document, champ: &str, valeur: Option<Option<T>>)
+            -> Result<(), Error>
+            where T: Serialize
+        {
+            match valeur {
+                Some(Some(inner)) => {
+                    let valeur = match bson::to_bson(&inner) {
+                        Ok(inner) => inner,
+                        Err(e) => Err(Error::String(format!("TransactionMajClient Erreur conversion champ {} : {:?}", champ, e)))?
+                    };
+                    set_ops.insert(champ, valeur);
+                },
+                Some(None) => { unset_ops.insert(champ, true); },
+                None => ()
+            }
+            Ok(())
         }
+
+        appliquer(&mut set_ops, &mut unset_ops, "descriptif", self.descriptif)?;
+        appliquer(&mut set_ops, &mut unset_ops, "roles", self.roles)?;
+        appliquer(&mut set_ops, &mut unset_ops, "domaines", self.domaines)?;
+        appliquer(&mut set_ops, &mut unset_ops, "data_chiffre", self.data_chiffre)?;
+        appliquer(&mut set_ops, &mut unset_ops, "actif", self.actif)?;
+        appliquer(&mut set_ops, &mut unset_ops, "suspendu", self.suspendu)?;
+        appliquer(&mut set_ops, &mut unset_ops, "quota", self.quota)?;
+        appliquer(&mut set_ops, &mut unset_ops, "plan_id", self.plan_id)?;
+        appliquer(&mut set_ops, &mut unset_ops, "prepaye", self.prepaye)?;
+
+        match self.expiration {
+            Some(Some(inner)) => {
+                let expiration = match Utc.timestamp_opt(inner, 0).single() {
+                    Some(inner) => inner,
+                    None => Err(Error::String(format!("TransactionMajClient Expiration invalide : {}", inner)))?
+                };
+                set_ops.insert("expiration", expiration);
+            },
+            Some(None) => { unset_ops.insert("expiration", true); },
+            None => ()
+        }
+
+        Ok((set_ops, unset_ops))
+    }
+}
+
+async fn transaction_maj_client<M>(_gestionnaire: &GestionnaireDomaineHebergement, middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionMajClient = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    let filtre = doc! {"idmg": &message_recu.idmg};
+
+    let (set_ops, unset_ops) = message_recu.get_operations()?;
+    let mut ops = doc! {"$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}};
+    if ! set_ops.is_empty() {
+        ops.insert("$set", set_ops);
+    }
+    if ! unset_ops.is_empty() {
+        ops.insert("$unset", unset_ops);
+    }
+
+    let collection = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    collection.update_one(filtre, ops, None).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionSupprimerClient {
+    pub idmg: String,
+}
+
+async fn transaction_supprimer_client<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                         middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionSupprimerClient = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    let idmg = message_recu.idmg;
+    let transaction_id = transaction.transaction.id;
+    let date_suppression = transaction.transaction.estampille;
+
+    // Archiver le client. La cle de l'archive est la transaction pour supporter la regeneration.
+    let filtre = doc! {"idmg": &idmg};
+    let collection_clients = middleware.get_collection(constantes::COLLECTION_CLIENTS_NOM)?;
+    if let Some(mut client) = collection_clients.find_one(filtre.clone(), None).await? {
+        client.remove("_id");
+        client.insert("date_archivage", date_suppression);
+        let filtre_archive = doc! {"transaction_id": &transaction_id};
+        let ops = doc! {
+            "$setOnInsert": {"transaction_id": &transaction_id},
+            "$set": client,
+        };
+        let collection_archives = middleware.get_collection(constantes::COLLECTION_CLIENTS_ARCHIVES_NOM)?;
+        let options = UpdateOptions::builder().upsert(true).build();
+        collection_archives.update_one(filtre_archive, ops, options).await?;
+        collection_clients.delete_one(filtre, None).await?;
+    }
+
+    // Marquer tous les fichiers du client pour retrait
+    let filtre_fichiers = doc! {"idmg": &idmg, constantes::CHAMP_RETIRE: {"$ne": true}};
+    let ops = doc! {
+        "$set": {
+            constantes::CHAMP_RETIRE: true,
+            constantes::CHAMP_DATE_RETRAIT: date_suppression,
+        },
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
     };
+    let collection_fichiers = middleware.get_collection(constantes::COLLECTION_FICHIERS_NOM)?;
+    collection_fichiers.update_many(filtre_fichiers, ops, None).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionDemandeHebergement {
+    pub idmg: String,
+    pub descriptif: Option<String>,
+    pub roles: Option<Vec<String>>,
+    pub domaines: Option<Vec<String>>,
+}
+
+async fn transaction_demande_hebergement<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                            middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionDemandeHebergement = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    // Le id de la transaction sert d'identificateur a la demande
+    let demande_id = transaction.transaction.id;
+    let filtre = doc! {"demande_id": &demande_id};
+    let ops = doc! {
+        "$setOnInsert": {
+            "demande_id": &demande_id,
+            "idmg": message_recu.idmg,
+            "descriptif": message_recu.descriptif,
+            "roles": message_recu.roles,
+            "domaines": message_recu.domaines,
+            constantes::CHAMP_ETAT: constantes::ETAT_DEMANDE_ATTENTE,
+            CommonConstantes::CHAMP_CREATION: transaction.transaction.estampille,
+        },
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_DEMANDES_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
     collection.update_one(filtre, ops, options).await?;
 
     Ok(None)
 }
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionApprouverDemande {
+    pub demande_id: String,
+    /// Client cree par l'approbation. Absent des transactions anterieures, ou le client
+    /// etait cree par une transaction sauvegarderClient distincte.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idmg: Option<String>,
+    /// Remplace la description de la demande.
+    pub descriptif: Option<String>,
+    #[serde(default, with="optionepochseconds")]
+    pub expiration: Option<DateTime<Utc>>,
+    /// Remplace les roles de la demande.
+    pub roles: Option<Vec<String>>,
+    /// Remplace les domaines de la demande.
+    pub domaines: Option<Vec<String>>,
+    pub plan_id: Option<String>,
+}
+
+async fn transaction_approuver_demande<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                          middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionApprouverDemande = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    if let Some(idmg) = message_recu.idmg {
+        let client = TransactionSauvegarderClient {
+            idmg,
+            descriptif: message_recu.descriptif,
+            expiration: message_recu.expiration,
+            roles: message_recu.roles,
+            domaines: message_recu.domaines,
+            data_chiffre: None,
+            actif: Some(true),
+            suspendu: None,
+            quota: None,
+            plan_id: message_recu.plan_id,
+            prepaye: None,
+        };
+        sauvegarder_client(middleware, client).await?;
+    }
+
+    let filtre = doc! {"demande_id": &message_recu.demande_id};
+    let ops = doc! {
+        "$set": {
+            constantes::CHAMP_ETAT: constantes::ETAT_DEMANDE_APPROUVEE,
+            constantes::CHAMP_DATE_TRAITEMENT: transaction.transaction.estampille,
+        },
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_DEMANDES_NOM)?;
+    collection.update_one(filtre, ops, None).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionRefuserDemande {
+    pub demande_id: String,
+    pub raison: Option<String>,
+}
+
+async fn transaction_refuser_demande<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                        middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionRefuserDemande = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {"demande_id": &message_recu.demande_id};
+    let ops = doc! {
+        "$set": {
+            constantes::CHAMP_ETAT: constantes::ETAT_DEMANDE_REFUSEE,
+            "raison": message_recu.raison,
+            constantes::CHAMP_DATE_TRAITEMENT: transaction.transaction.estampille,
+        },
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_DEMANDES_NOM)?;
+    collection.update_one(filtre, ops, None).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionCreerInvitation {
+    pub code: String,
+    pub plan_id: Option<String>,
+    pub roles: Option<Vec<String>>,
+    pub domaines: Option<Vec<String>>,
+    /// Expiration de l'hebergement active avec le code.
+    #[serde(default, with="optionepochseconds")]
+    pub expiration: Option<DateTime<Utc>>,
+    /// Le code ne peut plus etre utilise apres cette date.
+    #[serde(default, with="optionepochseconds")]
+    pub expiration_code: Option<DateTime<Utc>>,
+    pub utilisations_max: Option<i64>,
+}
+
+async fn transaction_creer_invitation<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                         middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionCreerInvitation = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {"code": &message_recu.code};
+    let ops = doc! {
+        "$setOnInsert": {
+            CommonConstantes::CHAMP_CREATION: transaction.transaction.estampille,
+            "utilisations": 0,
+        },
+        "$set": {
+            "plan_id": message_recu.plan_id,
+            "roles": message_recu.roles,
+            "domaines": message_recu.domaines,
+            "expiration": message_recu.expiration,
+            "expiration_code": message_recu.expiration_code,
+            "utilisations_max": message_recu.utilisations_max,
+        },
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_INVITATIONS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionUtiliserInvitation {
+    pub code: String,
+    pub idmg: String,
+}
+
+async fn transaction_utiliser_invitation<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                            middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionUtiliserInvitation = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    // Une MilleGrille n'est comptee qu'une seule fois par code. La limite d'utilisations et l'expiration
+    // du code sont verifiees ici pour que deux utilisations concurrentes ne depassent pas la limite.
+    let filtre = doc! {
+        "code": &message_recu.code,
+        "idmgs": {"$ne": &message_recu.idmg},
+        "$and": [
+            {"$or": [{"utilisations_max": null}, {"$expr": {"$lt": ["$utilisations", "$utilisations_max"]}}]},
+            {"$or": [{"expiration_code": null}, {"expiration_code": {"$gt": transaction.transaction.estampille}}]},
+        ],
+    };
+    let ops = doc! {
+        "$inc": {"utilisations": 1},
+        "$push": {"idmgs": &message_recu.idmg},
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_INVITATIONS_NOM)?;
+    collection.update_one(filtre, ops, None).await?;
+
+    Ok(None)
+}
+
+async fn transaction_ajouter_service_catalogue<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                                  middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: ServiceCatalogueRow = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {"type": &message_recu.type_service, "nom": &message_recu.nom};
+    let ops = doc! {
+        "$setOnInsert": {CommonConstantes::CHAMP_CREATION: transaction.transaction.estampille},
+        "$set": {"descriptif": message_recu.descriptif},
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_CATALOGUE_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    Ok(None)
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransactionRetirerServiceCatalogue {
+    #[serde(rename = "type")]
+    pub type_service: String,
+    pub nom: String,
+}
+
+async fn transaction_retirer_service_catalogue<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                                  middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionRetirerServiceCatalogue = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {"type": &message_recu.type_service, "nom": &message_recu.nom};
+    let collection = middleware.get_collection(constantes::COLLECTION_CATALOGUE_NOM)?;
+    collection.delete_one(filtre, None).await?;
+
+    Ok(None)
+}
+
+async fn transaction_sauvegarder_plan<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                        middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: PlanHebergementRow = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+
+    let filtre = doc! {"plan_id": &message_recu.plan_id};
+    let quota = match message_recu.quota {
+        Some(inner) => Some(convertir_to_bson(inner)?),
+        None => None
+    };
+    let ops = doc! {
+        "$setOnInsert": {CommonConstantes::CHAMP_CREATION: transaction.transaction.estampille},
+        "$set": {
+            "nom": message_recu.nom,
+            "descriptif": message_recu.descriptif,
+            "quota": quota,
+            "roles": message_recu.roles,
+            "domaines": message_recu.domaines,
+            "duree_jours": message_recu.duree_jours,
+            "prix_mensuel": message_recu.prix_mensuel,
+            "prix_depassement_go": message_recu.prix_depassement_go,
+            "prix_transfert_go": message_recu.prix_transfert_go,
+        },
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_PLANS_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    Ok(None)
+}
+
+/// Releve finalise, calcule par la commande pour que la regeneration donne le meme releve. L'ajustement
+/// complete au montant du releve les ecritures deja faites pour le mois (client prepaye).
+#[derive(Serialize, Deserialize)]
+pub struct TransactionFinaliserReleve {
+    #[serde(flatten)]
+    pub calcul: CalculReleve,
+    /// Montant (cents) a debiter, negatif pour un credit. Absent si le client n'est pas prepaye.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ajustement_grand_livre: Option<i64>,
+}
+
+async fn transaction_finaliser_releve<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                         middleware: &M, transaction: TransactionValide)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionFinaliserReleve = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    let calcul = message_recu.calcul;
+
+    let filtre = doc! {"idmg": &calcul.idmg, "mois": &calcul.mois};
+    let mut releve = convertir_to_bson(&calcul)?;
+    releve.insert(constantes::CHAMP_ETAT, constantes::ETAT_RELEVE_FINALISE);
+    releve.insert("date_finalisation", transaction.transaction.estampille);
+    releve.insert("transaction_id", &transaction.transaction.id);
+    let ops = doc! {
+        "$setOnInsert": {CommonConstantes::CHAMP_CREATION: transaction.transaction.estampille},
+        "$set": releve,
+        "$currentDate": {CommonConstantes::CHAMP_MODIFICATION: true}
+    };
+    let collection = middleware.get_collection(constantes::COLLECTION_RELEVES_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+
+    // L'ajustement est conserve meme s'il est nul, il indique que le releve est regle au grand livre
+    if let Some(ajustement) = message_recu.ajustement_grand_livre {
+        let type_ecriture = match ajustement {
+            a if a < 0 => constantes::TYPE_ECRITURE_CREDIT,
+            _ => constantes::TYPE_ECRITURE_DEBIT
+        };
+        let ecriture = TransactionEcritureGrandLivre {
+            idmg: calcul.idmg,
+            montant: ajustement.abs(),
+            reference: Some(constantes::REFERENCE_ECRITURE_RELEVE.to_string()),
+            mois: Some(calcul.mois),
+            jour: None,
+        };
+        inserer_ecriture_grand_livre(middleware, &transaction, ecriture, type_ecriture).await?;
+    }
+
+    Ok(None)
+}
+
+/// Ecriture au grand livre d'un client (crediterClient ou debiterClient).
+#[derive(Serialize, Deserialize)]
+pub struct TransactionEcritureGrandLivre {
+    pub idmg: String,
+    /// Montant en cents, jamais negatif (nul pour un ajustement de releve deja regle).
+    pub montant: i64,
+    /// Reference externe (e.g. paiement, facture).
+    pub reference: Option<String>,
+    /// Mois du releve debite, format YYYY-MM.
+    pub mois: Option<String>,
+    /// Journee de consommation debitee, format YYYY-MM-DD.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jour: Option<String>,
+}
+
+/// Conserve une ecriture au grand livre. L'ecriture est identifiee par la transaction (une seule
+/// ecriture par transaction, la regeneration ne duplique pas les ecritures).
+async fn inserer_ecriture_grand_livre<M>(middleware: &M, transaction: &TransactionValide,
+                                         ecriture: TransactionEcritureGrandLivre, type_ecriture: &str)
+    -> Result<(), Error>
+    where M: MongoDao
+{
+    let transaction_id = &transaction.transaction.id;
+    let filtre = doc! {"transaction_id": transaction_id};
+    let mut ecriture_doc = doc! {
+        "transaction_id": transaction_id,
+        "idmg": ecriture.idmg,
+        "type": type_ecriture,
+        "montant": ecriture.montant,
+        "reference": ecriture.reference,
+        "mois": ecriture.mois,
+        "date": transaction.transaction.estampille,
+    };
+    if let Some(jour) = ecriture.jour {
+        ecriture_doc.insert("jour", jour);
+    }
+    let ops = doc! {"$setOnInsert": ecriture_doc};
+    let collection = middleware.get_collection(constantes::COLLECTION_GRAND_LIVRE_NOM)?;
+    let options = UpdateOptions::builder().upsert(true).build();
+    collection.update_one(filtre, ops, options).await?;
+    Ok(())
+}
+
+async fn transaction_ecriture_grand_livre<M>(_gestionnaire: &GestionnaireDomaineHebergement,
+                                             middleware: &M, transaction: TransactionValide, type_ecriture: &str)
+    -> Result<Option<MessageMilleGrillesBufferDefault>, Error>
+    where M: MongoDao
+{
+    let message_recu: TransactionEcritureGrandLivre = serde_json::from_str(transaction.transaction.contenu.as_str())?;
+    inserer_ecriture_grand_livre(middleware, &transaction, message_recu, type_ecriture).await?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn operations(contenu: &str) -> (Document, Document) {
+        let transaction: TransactionMajClient = serde_json::from_str(contenu).unwrap();
+        transaction.get_operations().unwrap()
+    }
+
+    #[test]
+    fn test_get_operations_champs_absents() {
+        let (set_ops, unset_ops) = operations(r#"{"idmg": "zTestIdmg"}"#);
+        assert!(set_ops.is_empty());
+        assert!(unset_ops.is_empty());
+    }
+
+    #[test]
+    fn test_get_operations_set() {
+        let (set_ops, unset_ops) = operations(r#"{"idmg": "zTestIdmg", "descriptif": "Client test", "suspendu": true, "expiration": 1767225600}"#);
+        assert_eq!(Some("Client test"), set_ops.get_str("descriptif").ok());
+        assert_eq!(Some(true), set_ops.get_bool("suspendu").ok());
+        let expiration = set_ops.get_datetime("expiration").unwrap();
+        assert_eq!(1767225600000, expiration.timestamp_millis());
+        assert!(! set_ops.contains_key("roles"));
+        assert!(unset_ops.is_empty());
+    }
+
+    #[test]
+    fn test_get_operations_null_retire_champ() {
+        let (set_ops, unset_ops) = operations(r#"{"idmg": "zTestIdmg", "plan_id": null, "expiration": null, "actif": false}"#);
+        assert!(unset_ops.contains_key("plan_id"));
+        assert!(unset_ops.contains_key("expiration"));
+        assert!(! unset_ops.contains_key("actif"));
+        assert_eq!(Some(false), set_ops.get_bool("actif").ok());
+        assert!(! set_ops.contains_key("plan_id"));
+    }
+
+    #[test]
+    fn test_get_operations_expiration_invalide() {
+        let transaction: TransactionMajClient = serde_json::from_str(r#"{"idmg": "zTestIdmg", "expiration": 9223372036854775807}"#).unwrap();
+        assert!(transaction.get_operations().is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use log::debug;
+use millegrilles_common_rust::certificats::{ValidateurX509, VerificateurPermissions};
+use millegrilles_common_rust::chrono::Utc;
+use millegrilles_common_rust::constantes::{DELEGATION_GLOBALE_PROPRIETAIRE, Securite};
+use millegrilles_common_rust::error::Error;
+use millegrilles_common_rust::generateur_messages::GenerateurMessages;
+use millegrilles_common_rust::millegrilles_cryptographie::messages_structs::{MessageMilleGrillesBufferDefault, MessageMilleGrillesOwned, MessageValidable};
+use millegrilles_common_rust::millegrilles_cryptographie::x509::EnveloppeCertificat;
+use millegrilles_common_rust::recepteur_messages::MessageValide;
+
+/// Ecart maximal (secondes) entre l'estampille d'une requete client et l'heure courante.
+const DELAI_ESTAMPILLE_REQUETE_CLIENT: i64 = 300;
+
+/// Verifie que le message provient d'un operateur de l'hebergement (exchange 3.protege/4.secure
+/// ou certificat proprietaire).
+pub fn verifier_acces_operateur(message: &MessageValide) -> Result<bool, Error> {
+    if message.certificat.verifier_delegation_globale(DELEGATION_GLOBALE_PROPRIETAIRE)? {
+        Ok(true)
+    } else if message.certificat.verifier_exchanges(vec![Securite::L3Protege, Securite::L4Secure])? {
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+/// Requete signee par une MilleGrille cliente dont la signature et la chaine de certificats
+/// ont ete verifiees.
+pub struct RequeteClientVerifiee {
+    /// IDMG calcule a partir du certificat CA de la requete.
+    pub idmg: String,
+    /// Certificat de l'instance qui a signe la requete.
+    pub enveloppe: Arc<EnveloppeCertificat>,
+    pub requete: MessageMilleGrillesOwned,
+}
+
+pub enum ResultatVerificationClient {
+    Valide(RequeteClientVerifiee),
+    /// Reponse d'erreur a retourner au client.
+    Refusee(MessageMilleGrillesBufferDefault),
+}
+
+/// Verifie une requete signee par une MilleGrille externe : signature, certificat CA (idmg),
+/// chaine de certificats, role core et niveau 4.secure. Avec une action, verifie aussi l'action
+/// et l'estampille de la requete (empeche sa reutilisation pour une autre action ou plus tard).
+/// Sans action (getTokenJwt), la verification d'origine est conservee.
+///
+/// Codes d'erreur : 1 a 9 pour la verification d'origine, 100 et plus pour l'action et l'estampille.
+pub async fn verifier_requete_client<M>(middleware: &M, mut requete_client: MessageMilleGrillesOwned, action: Option<&str>)
+    -> Result<ResultatVerificationClient, Error>
+    where M: GenerateurMessages + ValidateurX509
+{
+    if ! requete_client.verifier_signature().is_ok() {
+        debug!("verifier_requete_client Signature invalide");
+        return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(1), None, Some("Signature requete invalide"))?))
+    };
+
+    if let Some(action) = action {
+        let action_requete = requete_client.routage.as_ref().and_then(|r| r.action.as_deref());
+        if action_requete != Some(action) {
+            debug!("verifier_requete_client Action requete {:?} ne correspond pas a {}", action_requete, action);
+            return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(100), None, Some("Action requete invalide"))?))
+        }
+
+        let ecart = Utc::now() - requete_client.estampille;
+        if ecart.num_seconds().abs() > DELAI_ESTAMPILLE_REQUETE_CLIENT {
+            debug!("verifier_requete_client Estampille requete hors delai ({} secondes)", ecart.num_seconds());
+            return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(101), None, Some("Estampille requete expiree"))?))
+        }
+    }
+
+    debug!("verifier_requete_client Charger enveloppe IDMG");
+    let (enveloppe_idmg, ca_pem) = match requete_client.millegrille.clone() {
+        Some(ca_pem) => {
+            match middleware.charger_enveloppe(&vec![ca_pem.clone()], None, None).await {
+                Ok(inner) => (inner, ca_pem),
+                Err(e) => {
+                    debug!("verifier_requete_client Certificat IDMG invalide : {:?}", e);
+                    return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(4), None, Some("Certificat IDMG invalide"))?))
+                }
+            }
+        },
+        None => {
+            debug!("verifier_requete_client Certificat IDMG manquant");
+            return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(3), None, Some("Certificat IDMG manquant"))?))
+        }
+    };
+
+    debug!("verifier_requete_client Verifier enveloppe requete");
+    let enveloppe_requete = match requete_client.certificat.as_ref() {
+        Some(inner) => {
+            match middleware.charger_enveloppe(inner, None, Some(ca_pem.as_str())).await {
+                Ok(inner) => inner,
+                Err(e) => {
+                    debug!("verifier_requete_client Certificat requete invalide : {:?}", e);
+                    return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(7), None, Some("Certificat requete invalide"))?))
+                }
+            }
+        },
+        None => {
+            debug!("verifier_requete_client Certificat requete manquant");
+            return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(6), None, Some("Certificat requete manquant"))?))
+        }
+    };
+
+    let certificat_requete_valide = middleware.valider_chaine(
+        enveloppe_requete.as_ref(), Some(enveloppe_idmg.as_ref()), true)?;
+    if ! certificat_requete_valide {
+        debug!("verifier_requete_client Certificat requete invalide");
+        return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(9), None, Some("Certificat requete invalide"))?))
+    }
+
+    if requete_client.pubkey.as_str() != enveloppe_requete.fingerprint()?.as_str() {
+        return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(8), None, Some("Mismatch certificat requete"))?))
+    }
+
+    if !enveloppe_requete.verifier_roles_string(vec!["core".to_string()])? {
+        Err(Error::Str("verifier_requete_client Seul le role core est supporte"))?
+    }
+    if !enveloppe_requete.verifier_exchanges(vec![Securite::L4Secure])? {
+        Err(Error::Str("verifier_requete_client Seul le niveau 4.secure est supporte"))?
+    }
+
+    let idmg = enveloppe_idmg.calculer_idmg()?;
+    if enveloppe_requete.idmg()? != idmg.as_str() {
+        return Ok(ResultatVerificationClient::Refusee(middleware.reponse_err(Some(5), None, Some("Mismatch idmg certificat/ca"))?))
+    }
+
+    Ok(ResultatVerificationClient::Valide(RequeteClientVerifiee {
+        idmg,
+        enveloppe: enveloppe_requete,
+        requete: requete_client,
+    }))
+}